@@ -1,6 +1,8 @@
 // Map function:
-// Receives a single line of input.
-// Should return an array of [key, value] pairs to emit from this line.
+// Receives a single line of input, and as a second argument an object
+// `{ file, line }` with the file name and line number it was read from.
+// Should return an array of [key, value] pairs to emit from this line,
+// or call `await emit(key, value)` for each pair and return nothing.
 const map = async line => line
     .toLowerCase()
     .replace(/[^\p{L}\p{N}]+/gu, ' ') // only letters and numbers
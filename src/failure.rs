@@ -0,0 +1,200 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Number of failed records or keys listed in the summary of each phase
+const MAX_REPORTED: usize = 10;
+
+/// What to do when the script throws while processing records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum OnError {
+    /// Stop the job with an error.
+    #[default]
+    Fail,
+    /// Drop the failed records, report them and carry on.
+    Skip,
+    /// Like `skip`, but also write the failures to the dead-letter file.
+    DeadLetter,
+}
+
+impl Display for OnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnError::Fail => write!(f, "fail"),
+            OnError::Skip => write!(f, "skip"),
+            OnError::DeadLetter => write!(f, "dead-letter"),
+        }
+    }
+}
+
+/// Phase of the job a failure happened in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Map,
+    Reduce,
+}
+
+impl Phase {
+    /// What is lost when this phase fails.
+    fn unit(&self) -> &'static str {
+        match self {
+            Phase::Map => "records",
+            Phase::Reduce => "keys",
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Map => write!(f, "map"),
+            Phase::Reduce => write!(f, "reduce"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PhaseFailures {
+    failures: usize,
+    lost: usize,
+    first_error: String,
+    failed: Vec<String>,
+}
+
+/// Failures of a job, applied against the `--on-error` policy.
+#[derive(Debug)]
+pub struct Failures {
+    policy: OnError,
+    retries: u32,
+    phases: Mutex<BTreeMap<Phase, PhaseFailures>>,
+    dead_letter: Option<Mutex<BufWriter<File>>>,
+}
+
+impl Failures {
+    /// The dead-letter file is only created with the `dead-letter` policy.
+    pub fn new(policy: OnError, retries: u32, dead_letter: &Path) -> Result<Self> {
+        let dead_letter = match policy {
+            OnError::DeadLetter => {
+                let file = File::create(dead_letter).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed to create dead-letter file {}: {}",
+                        dead_letter.display(),
+                        e
+                    )
+                })?;
+                Some(Mutex::new(BufWriter::new(file)))
+            }
+            OnError::Fail | OnError::Skip => None,
+        };
+        Ok(Failures {
+            policy,
+            retries,
+            phases: Mutex::new(BTreeMap::new()),
+            dead_letter,
+        })
+    }
+
+    /// Record a failure that lost `lost` records or keys. With the `fail` policy the
+    /// error is returned so the job stops; otherwise the failure is counted, `label`
+    /// names the failed record or key in the summary, and the failure is written to
+    /// the dead-letter file together with `details`.
+    pub fn record(
+        &self,
+        phase: Phase,
+        lost: usize,
+        error: &str,
+        label: Option<String>,
+        details: serde_json::Value,
+    ) -> Result<()> {
+        if self.policy == OnError::Fail {
+            return Err(anyhow::anyhow!("Error in {} phase: {}", phase, error));
+        }
+        self.count(phase, lost, error, label, details)
+    }
+
+    /// Record an input record dropped on purpose, like a line that isn't valid JSON.
+    /// It counts as a lost record whatever the policy, but never stops the job.
+    pub fn skip(
+        &self,
+        phase: Phase,
+        error: &str,
+        label: String,
+        details: serde_json::Value,
+    ) -> Result<()> {
+        self.count(phase, 1, error, Some(label), details)
+    }
+
+    fn count(
+        &self,
+        phase: Phase,
+        lost: usize,
+        error: &str,
+        label: Option<String>,
+        details: serde_json::Value,
+    ) -> Result<()> {
+        {
+            let mut phases = self.phases.lock().unwrap();
+            let failures = phases.entry(phase).or_default();
+            if failures.failures == 0 {
+                failures.first_error = error.lines().next().unwrap_or_default().to_string();
+            }
+            failures.failures += 1;
+            failures.lost += lost;
+            if let Some(label) = label
+                && failures.failed.len() < MAX_REPORTED
+            {
+                failures.failed.push(label);
+            }
+        }
+
+        if let Some(dead_letter) = &self.dead_letter {
+            let mut entry = serde_json::json!({ "phase": phase.to_string(), "error": error });
+            if let (Some(entry), serde_json::Value::Object(details)) = (entry.as_object_mut(), details) {
+                entry.extend(details);
+            }
+            // Flushed entry by entry, so the entries are kept however the job ends
+            let mut writer = dead_letter.lock().unwrap();
+            writeln!(writer, "{}", entry)
+                .and_then(|()| writer.flush())
+                .map_err(|e| anyhow::anyhow!("Failed to write dead-letter entry: {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self) -> OnError {
+        self.policy
+    }
+
+    /// Number of times a record is retried before it counts as failed.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phases.lock().unwrap().is_empty()
+    }
+
+    /// Print a summary of the failures per phase.
+    pub fn finish(&self) -> Result<()> {
+        for (phase, failures) in self.phases.lock().unwrap().iter() {
+            let lost = match failures.lost {
+                0 => String::new(),
+                lost => format!(", {} {} lost", lost, phase.unit()),
+            };
+            eprintln!(
+                "{} phase: {} failures{} (first error: {})",
+                phase, failures.failures, lost, failures.first_error
+            );
+            if !failures.failed.is_empty() {
+                let more = if failures.failures > failures.failed.len() { ", ..." } else { "" };
+                eprintln!("  failed {}: {}{}", phase.unit(), failures.failed.join(", "), more);
+            }
+        }
+        Ok(())
+    }
+}
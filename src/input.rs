@@ -0,0 +1,756 @@
+use crate::failure::{Failures, Phase};
+use crate::js;
+use anyhow::Result;
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use clap::ValueEnum;
+use csv_core::{ReadRecordResult, ReaderBuilder};
+use flume::Sender;
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::task::JoinSet;
+use tracing::debug;
+
+pub type InputReader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+
+/// Where input records are read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Stdin => write!(f, "-"),
+            Source::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Expand the `-f` arguments into a list of sources.
+///
+/// Directories are walked recursively and patterns containing glob metacharacters
+/// are expanded. Matches are sorted so the read order is stable across runs.
+pub fn resolve_sources(paths: &[String]) -> Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    for path in paths {
+        if path == "-" {
+            if !sources.contains(&Source::Stdin) {
+                sources.push(Source::Stdin);
+            }
+            continue;
+        }
+
+        let literal = Path::new(path);
+        if literal.exists() {
+            push_path(literal, &mut sources)?;
+        } else if path.contains(['*', '?', '[']) {
+            let mut matches = glob::glob(path)
+                .map_err(|e| anyhow::anyhow!("Invalid glob pattern {}: {}", path, e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow::anyhow!("Failed to expand glob {}: {}", path, e))?;
+            if matches.is_empty() {
+                return Err(anyhow::anyhow!("No files match {}", path));
+            }
+            matches.sort();
+            for entry in matches {
+                push_path(&entry, &mut sources)?;
+            }
+        } else {
+            return Err(anyhow::anyhow!(
+                "Failed to open file {}: No such file or directory",
+                path
+            ));
+        }
+    }
+    Ok(sources)
+}
+
+fn push_path(path: &Path, sources: &mut Vec<Source>) -> Result<()> {
+    if !path.is_dir() {
+        sources.push(Source::File(path.to_path_buf()));
+        return Ok(());
+    }
+    for entry in walkdir::WalkDir::new(path).sort_by_file_name() {
+        let entry = entry
+            .map_err(|e| anyhow::anyhow!("Failed to read directory {}: {}", path.display(), e))?;
+        if entry.file_type().is_file() {
+            sources.push(Source::File(entry.into_path()));
+        }
+    }
+    Ok(())
+}
+
+/// Compression formats that are decoded transparently when reading input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+/// Length of the longest magic number `Compression::from_magic` checks
+const MAGIC_LEN: usize = 6;
+
+impl Compression {
+    fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if let [b'B', b'Z', b'h', b'1'..=b'9', ..] = bytes {
+            // The block size digit makes it unlikely that a text file starting with BZh matches
+            Some(Compression::Bzip2)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" => Some(Compression::Bzip2),
+            "xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+}
+
+/// Read the first `MAGIC_LEN` bytes, or fewer at the end of the input. A single read
+/// may return less, e.g. from a pipe.
+async fn read_magic<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    reader.take(MAGIC_LEN as u64).read_to_end(&mut magic).await?;
+    Ok(magic)
+}
+
+/// Open a source for reading, decompressing it if it is gzip, zstd, bzip2 or xz.
+/// The format is detected from the file extension, falling back to the magic bytes for
+/// stdin and files without a known extension.
+pub async fn open(source: &Source) -> Result<InputReader> {
+    let (mut raw, path): (Box<dyn AsyncRead + Unpin + Send>, Option<&Path>) = match source {
+        Source::Stdin => (Box::new(tokio::io::stdin()), None),
+        Source::File(path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to open file {}: {}", path.display(), e))?;
+            (Box::new(file), Some(path.as_path()))
+        }
+    };
+
+    let compression = match path.and_then(Compression::from_extension) {
+        Some(compression) => Some(compression),
+        None => {
+            let magic = read_magic(&mut raw)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", source, e))?;
+            let compression = Compression::from_magic(&magic);
+            // Put the magic bytes back in front of the rest of the input
+            raw = Box::new(std::io::Cursor::new(magic).chain(raw));
+            compression
+        }
+    };
+    let reader = BufReader::new(raw);
+
+    let decoder: Box<dyn AsyncRead + Unpin + Send> = match compression {
+        None => return Ok(reader),
+        Some(Compression::Gzip) => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Some(Compression::Zstd) => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Some(Compression::Bzip2) => {
+            let mut decoder = BzDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Some(Compression::Xz) => {
+            let mut decoder = XzDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+    };
+    debug!("Decompressing {} as {:?}", source, compression);
+    Ok(BufReader::new(decoder))
+}
+
+/// Options for parsing delimited (CSV/TSV) input.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub escape: Option<u8>,
+    pub header: bool,
+}
+
+/// What to do with records that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Default)]
+pub enum InvalidUtf8 {
+    /// Drop the record and report it.
+    #[default]
+    Skip,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+    /// Stop the job with an error.
+    Fail,
+    /// Pass every record to `map` as a `Uint8Array`.
+    Bytes,
+}
+
+impl Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidUtf8::Skip => write!(f, "skip"),
+            InvalidUtf8::Lossy => write!(f, "lossy"),
+            InvalidUtf8::Fail => write!(f, "fail"),
+            InvalidUtf8::Bytes => write!(f, "bytes"),
+        }
+    }
+}
+
+/// Position of a record within its source, used in error messages.
+#[derive(Debug, Clone, Copy)]
+enum Location<'a> {
+    Line(usize, &'a str),
+    Byte(u64, &'a str),
+}
+
+impl Location<'_> {
+    /// Names the record in the failure summary, like failures reported by `map`
+    fn label(&self) -> String {
+        match self {
+            Location::Line(line, file) => format!("{}:{}", file, line),
+            Location::Byte(offset, file) => format!("{}@{}", file, offset),
+        }
+    }
+
+    /// The record's `meta`, for the dead-letter file
+    fn meta(&self) -> serde_json::Value {
+        match self {
+            Location::Line(line, file) => serde_json::json!({ "file": file, "line": line }),
+            Location::Byte(offset, file) => serde_json::json!({ "file": file, "offset": offset }),
+        }
+    }
+}
+
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Line(line, file) => write!(f, "line {} of {}", line, file),
+            Location::Byte(offset, file) => write!(f, "byte offset {} of {}", offset, file),
+        }
+    }
+}
+
+/// Turns raw records into the values passed to `map`, applying the NDJSON and
+/// `--invalid-utf8` settings. Records it drops are recorded as lost map records.
+#[derive(Debug)]
+pub struct RecordDecoder {
+    ndjson: bool,
+    invalid_utf8: InvalidUtf8,
+    failures: Arc<Failures>,
+}
+
+impl RecordDecoder {
+    pub fn new(ndjson: bool, invalid_utf8: InvalidUtf8, failures: Arc<Failures>) -> Self {
+        RecordDecoder {
+            ndjson,
+            invalid_utf8,
+            failures,
+        }
+    }
+
+    /// Count a dropped record as a failure of the map phase
+    fn skip(&self, error: String, input: String, at: Location<'_>) -> Result<()> {
+        let details = serde_json::json!({ "input": input, "meta": at.meta() });
+        self.failures.skip(Phase::Map, &error, at.label(), details)
+    }
+
+    /// Decode a record. Returns `Ok(None)` for records that should be skipped.
+    fn decode(&self, bytes: Vec<u8>, at: Location<'_>) -> Result<Option<js::Value>> {
+        if self.invalid_utf8 == InvalidUtf8::Bytes {
+            return Ok(Some(js::Value::Bytes(bytes)));
+        }
+        let Some(text) = self.decode_text(bytes, at)? else {
+            return Ok(None);
+        };
+        if !self.ndjson {
+            return Ok(Some(js::Value::String(text)));
+        }
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(json) => Ok(Some(js::Value::from(json))),
+            Err(e) => {
+                self.skip(format!("Error parsing JSON on {}: {}", at, e), text, at)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Decode a record as UTF-8 text according to the `--invalid-utf8` policy.
+    fn decode_text(&self, bytes: Vec<u8>, at: Location<'_>) -> Result<Option<String>> {
+        let e = match String::from_utf8(bytes) {
+            Ok(text) => return Ok(Some(text)),
+            Err(e) => e,
+        };
+        match self.invalid_utf8 {
+            InvalidUtf8::Lossy => Ok(Some(String::from_utf8_lossy(e.as_bytes()).into_owned())),
+            InvalidUtf8::Fail => Err(anyhow::anyhow!(
+                "Invalid UTF-8 on {}: {}",
+                at,
+                e.utf8_error()
+            )),
+            InvalidUtf8::Skip | InvalidUtf8::Bytes => {
+                let error = format!("Invalid UTF-8 on {}: {}", at, e.utf8_error());
+                let input = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                self.skip(error, input, at)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// How input is divided into records.
+#[derive(Debug, Clone)]
+pub enum Framing {
+    /// One record per line.
+    Lines,
+    /// Records are terminated by an arbitrary byte string.
+    Separator(Vec<u8>),
+    /// Records are separated by one or more blank lines.
+    Paragraph,
+    /// A record starts at every line matching the pattern and spans the
+    /// following lines up to the next match.
+    RecordStart(Regex),
+}
+
+impl Framing {
+    /// Build the framing from the `--record-separator` and `--record-start` flags.
+    pub fn from_flags(separator: Option<&str>, start: Option<&str>) -> Result<Self> {
+        match (separator, start) {
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "--record-separator and --record-start cannot be used together"
+            )),
+            (Some(""), None) => Ok(Framing::Paragraph),
+            (Some(sep), None) => match unescape(sep)? {
+                sep if sep == b"\n" => Ok(Framing::Lines),
+                sep => Ok(Framing::Separator(sep)),
+            },
+            (None, Some(pattern)) => Regex::new(pattern)
+                .map(Framing::RecordStart)
+                .map_err(|e| anyhow::anyhow!("Invalid --record-start pattern: {}", e)),
+            (None, None) => Ok(Framing::Lines),
+        }
+    }
+}
+
+/// Interpret `\0`, `\n`, `\r`, `\t`, `\\` and `\xHH` escapes in a separator.
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('0') => out.push(0),
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('t') => out.push(b'\t'),
+            Some('\\') => out.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| anyhow::anyhow!("Invalid escape \\x{} in separator", hex))?;
+                out.push(byte);
+            }
+            Some(c) => return Err(anyhow::anyhow!("Invalid escape \\{} in separator", c)),
+            None => return Err(anyhow::anyhow!("Separator ends with a lone backslash")),
+        }
+    }
+    Ok(out)
+}
+
+/// Splits a byte stream into records according to a [`Framing`], keeping track
+/// of the line each record starts on.
+struct RecordReader<'a, R> {
+    reader: R,
+    framing: &'a Framing,
+    line: usize,
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> RecordReader<'a, R> {
+    fn new(reader: R, framing: &'a Framing) -> Self {
+        RecordReader {
+            reader,
+            framing,
+            line: 0,
+            pending: None,
+        }
+    }
+
+    /// Read the next line without its terminator. Returns false at end of input.
+    async fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+        if self.reader.read_until(b'\n', buf).await? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read the next record and the line number it starts on.
+    async fn next(&mut self) -> std::io::Result<Option<(Vec<u8>, usize)>> {
+        match self.framing {
+            Framing::Lines => {
+                let mut buf = Vec::new();
+                if !self.read_line(&mut buf).await? {
+                    return Ok(None);
+                }
+                Ok(Some((buf, self.line)))
+            }
+            Framing::Separator(sep) => {
+                let start = self.line + 1;
+                let last = *sep.last().expect("separator is not empty");
+                let mut buf = Vec::new();
+                while self.reader.read_until(last, &mut buf).await? > 0 {
+                    if buf.ends_with(sep) {
+                        self.line += buf.iter().filter(|&&b| b == b'\n').count();
+                        buf.truncate(buf.len() - sep.len());
+                        return Ok(Some((buf, start)));
+                    }
+                }
+                self.line += buf.iter().filter(|&&b| b == b'\n').count();
+                Ok((!buf.is_empty()).then_some((buf, start)))
+            }
+            Framing::Paragraph => {
+                let mut record = Vec::new();
+                let mut start = 0;
+                let mut line = Vec::new();
+                while self.read_line(&mut line).await? {
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        if record.is_empty() {
+                            line.clear();
+                            continue;
+                        }
+                        break;
+                    }
+                    if record.is_empty() {
+                        start = self.line;
+                    } else {
+                        record.push(b'\n');
+                    }
+                    record.append(&mut line);
+                }
+                Ok((!record.is_empty()).then_some((record, start)))
+            }
+            Framing::RecordStart(pattern) => {
+                let (mut record, start) = match self.pending.take() {
+                    Some(pending) => pending,
+                    None => {
+                        let mut line = Vec::new();
+                        if !self.read_line(&mut line).await? {
+                            return Ok(None);
+                        }
+                        (line, self.line)
+                    }
+                };
+                let mut line = Vec::new();
+                while self.read_line(&mut line).await? {
+                    if pattern.is_match(&line) {
+                        self.pending = Some((line, self.line));
+                        break;
+                    }
+                    record.push(b'\n');
+                    record.append(&mut line);
+                }
+                Ok(Some((record, start)))
+            }
+        }
+    }
+}
+
+/// Stream records into the map channel, divided according to `framing`.
+pub async fn read_records<R: AsyncBufRead + Unpin>(
+    reader: R,
+    file: &Arc<str>,
+    framing: &Framing,
+    decoder: &RecordDecoder,
+    tx: &Sender<js::MapItem>,
+) -> Result<()> {
+    let mut records = RecordReader::new(reader, framing);
+    while let Some((bytes, line_no)) = records.next().await? {
+        let Some(value) = decoder.decode(bytes, Location::Line(line_no, file))? else {
+            continue;
+        };
+        let item = js::MapItem {
+            value,
+            file: file.clone(),
+            line: Some(line_no),
+            offset: None,
+        };
+        if tx.send_async(item).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Return the length of `source` if it is an uncompressed regular file larger than
+/// `split_size`, meaning it can be read as several byte ranges in parallel.
+pub async fn split_len(source: &Source, split_size: u64) -> Option<u64> {
+    let Source::File(path) = source else {
+        return None;
+    };
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if split_size == 0 || !metadata.is_file() || metadata.len() <= split_size {
+        return None;
+    }
+    if Compression::from_extension(path).is_some() {
+        return None;
+    }
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    let magic = read_magic(&mut file).await.ok()?;
+    if Compression::from_magic(&magic).is_some() {
+        return None;
+    }
+    Some(metadata.len())
+}
+
+/// Read a file as byte-range splits of `split_size` bytes, with up to `readers`
+/// splits being read concurrently.
+///
+/// Splits are aligned to newlines: a line belongs to the split its first byte falls in,
+/// so every reader skips the partial line at the start of its range and reads past the
+/// end of the range to finish its last line. Records carry their byte offset instead of
+/// a line number, which is not known without reading the preceding splits.
+pub async fn read_splits(
+    path: &Path,
+    file: &Arc<str>,
+    len: u64,
+    split_size: u64,
+    readers: usize,
+    decoder: &Arc<RecordDecoder>,
+    tx: &Sender<js::MapItem>,
+) -> Result<()> {
+    let (split_tx, split_rx) = flume::unbounded();
+    for start in (0..len).step_by(split_size as usize) {
+        let _ = split_tx.send((start, (start + split_size).min(len)));
+    }
+    drop(split_tx);
+    debug!("Reading {} as {} splits", file, split_rx.len());
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..readers.max(1).min(split_rx.len()) {
+        let split_rx = split_rx.clone();
+        let path = path.to_path_buf();
+        let file = file.clone();
+        let decoder = decoder.clone();
+        let tx = tx.clone();
+        tasks.spawn(async move {
+            while let Ok((start, end)) = split_rx.recv_async().await {
+                read_split(&path, &file, start, end, &decoder, &tx).await?;
+                if tx.is_disconnected() {
+                    break;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+    }
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+    Ok(())
+}
+
+async fn read_split(
+    path: &Path,
+    file: &Arc<str>,
+    start: u64,
+    end: u64,
+    decoder: &RecordDecoder,
+    tx: &Sender<js::MapItem>,
+) -> Result<()> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open file {}: {}", path.display(), e))?;
+    f.seek(SeekFrom::Start(start.saturating_sub(1))).await?;
+    let mut reader = BufReader::new(f);
+    let mut buf = Vec::new();
+    let mut pos = start;
+    if start > 0 {
+        // skip the line that started in the previous split
+        pos = start - 1 + reader.read_until(b'\n', &mut buf).await? as u64;
+    }
+
+    while pos < end {
+        buf.clear();
+        let n = reader.read_until(b'\n', &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let offset = pos;
+        pos += n as u64;
+
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+        let record = std::mem::take(&mut buf);
+        let Some(value) = decoder.decode(record, Location::Byte(offset, file))? else {
+            continue;
+        };
+        let item = js::MapItem {
+            value,
+            file: file.clone(),
+            line: None,
+            offset: Some(offset),
+        };
+        if tx.send_async(item).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Stream delimited records into the map channel.
+///
+/// Quoted fields may contain delimiters and newlines. With a header, each record
+/// is sent as an object keyed by column name, otherwise as an array of fields.
+pub async fn read_csv<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    file: &Arc<str>,
+    opts: &CsvOptions,
+    decoder: &RecordDecoder,
+    tx: &Sender<js::MapItem>,
+) -> Result<()> {
+    let mut rdr = ReaderBuilder::new()
+        .delimiter(opts.delimiter)
+        .escape(opts.escape)
+        .build();
+    let mut output = vec![0u8; 4096];
+    let mut ends = vec![0usize; 64];
+    let (mut outlen, mut endlen) = (0, 0);
+    let mut header: Option<Vec<String>> = None;
+    let mut record_line = 1;
+
+    loop {
+        let input = reader.fill_buf().await?;
+        let (res, nin, nout, nend) =
+            rdr.read_record(input, &mut output[outlen..], &mut ends[endlen..]);
+        reader.consume(nin);
+        outlen += nout;
+        endlen += nend;
+
+        match res {
+            ReadRecordResult::InputEmpty => continue,
+            ReadRecordResult::OutputFull => output.resize(output.len() * 2, 0),
+            ReadRecordResult::OutputEndsFull => ends.resize(ends.len() * 2, 0),
+            ReadRecordResult::Record => {
+                let line = record_line;
+                record_line = rdr.line() as usize;
+                if opts.header && header.is_none() {
+                    let at = Location::Line(line, file);
+                    header = Some(header_fields(&output[..outlen], &ends[..endlen], at)?);
+                    outlen = 0;
+                    endlen = 0;
+                    continue;
+                }
+                let fields = split_fields(
+                    &output[..outlen],
+                    &ends[..endlen],
+                    decoder,
+                    Location::Line(line, file),
+                )?;
+                outlen = 0;
+                endlen = 0;
+                let Some(fields) = fields else {
+                    continue;
+                };
+                let value = match &header {
+                    Some(columns) => {
+                        let record: HashMap<String, js::Value> = fields
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, field)| {
+                                let key = columns.get(i).cloned().unwrap_or_else(|| i.to_string());
+                                (key, js::Value::String(field))
+                            })
+                            .collect();
+                        js::Value::Object(record)
+                    }
+                    None => js::Value::Array(fields.into_iter().map(js::Value::String).collect()),
+                };
+                let item = js::MapItem {
+                    value,
+                    file: file.clone(),
+                    line: Some(line),
+                    offset: None,
+                };
+                if tx.send_async(item).await.is_err() {
+                    break;
+                }
+            }
+            ReadRecordResult::End => break,
+        }
+    }
+    Ok(())
+}
+
+/// Column names of the header record. They must be valid UTF-8 whatever `--invalid-utf8`
+/// is, as skipping the header would take the first record for it.
+fn header_fields(output: &[u8], ends: &[usize], at: Location<'_>) -> Result<Vec<String>> {
+    let mut columns = Vec::with_capacity(ends.len());
+    let mut start = 0;
+    for &end in ends {
+        let column = std::str::from_utf8(&output[start..end])
+            .map_err(|e| anyhow::anyhow!("Invalid UTF-8 in the header on {}: {}", at, e))?;
+        columns.push(column.to_string());
+        start = end;
+    }
+    Ok(columns)
+}
+
+fn split_fields(
+    output: &[u8],
+    ends: &[usize],
+    decoder: &RecordDecoder,
+    at: Location<'_>,
+) -> Result<Option<Vec<String>>> {
+    let mut fields = Vec::with_capacity(ends.len());
+    let mut start = 0;
+    for &end in ends {
+        let Some(field) = decoder.decode_text(output[start..end].to_vec(), at)? else {
+            return Ok(None);
+        };
+        fields.push(field);
+        start = end;
+    }
+    Ok(Some(fields))
+}
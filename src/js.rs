@@ -1,15 +1,630 @@
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use llrt_core::modules::module_builder::ModuleBuilder;
 use llrt_core::vm::Vm;
 use rquickjs::{CatchResultExt, Coerced};
-use rquickjs::{Function, async_with, prelude::Promise};
+use rquickjs::loader::{Loader, Resolver};
+use rquickjs::{Function, Module, WriteOptions, async_with, prelude::Promise, qjs};
 use rquickjs::function::Async;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use swc_common::{
+    GLOBALS, SourceMap,
+    errors::{HANDLER, Handler},
+    sync::Lrc,
+};
 use flume::Receiver;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{error, instrument};
+use tracing::{debug, instrument};
+
+use crate::failure::{Failures, OnError, Phase};
+
+// Optional per-VM `setup` and `teardown` hooks, evaluated before the user script
+const LIFECYCLE_HOOKS: &str = r#"
+    const runSetup = async () => {
+        if (typeof setup === 'function') await setup();
+    };
+    const runTeardown = async () => {
+        if (typeof teardown === 'function') await teardown();
+    };
+"#;
+
+// Map and reduce drivers called by the engine for each job
+const MAP_REDUCE_WRAPPER: &str = r#"
+    // Hands a failed record to the engine's error policy, rethrowing when the job should stop
+    const reportFailure = (phase, label, details, error) => {
+        const stack = error instanceof Error ? error.stack : undefined;
+        if (!recordFailure(phase, label, String(error), { ...details, stack })) throw error;
+    };
+
+    // Fails the call once the record's deadline passes. Awaiting a promise that never settles
+    // is cut short by recordExpired. Runaway synchronous code is interrupted by the engine,
+    // which abandons the job it runs in, so fn is called from a job of its own.
+    const withTimeout = async (fn) => {
+        if (recordTimeout === 0) return await fn();
+        const id = startRecord();
+        try {
+            return await Promise.race([Promise.resolve().then(fn), recordExpired(id)]);
+        } finally {
+            if (endRecord(id)) throw new Error(`timed out after ${recordTimeout}s`);
+        }
+    };
+
+    // Calls fn again when it throws, up to `maxRetries` more times
+    const withRetries = async (fn) => {
+        for (let attempt = 0; ; attempt++) {
+            try {
+                return await fn();
+            } catch (error) {
+                if (attempt >= maxRetries) throw error;
+            }
+        }
+    };
+
+    // Strings are iterable too, but a string returned by map is a mistake, not pairs
+    const isIterable = (value) =>
+        typeof value !== 'string' &&
+        (typeof value[Symbol.asyncIterator] === 'function' ||
+            typeof value[Symbol.iterator] === 'function');
+
+    // When a failed record doesn't stop the job, its pairs are held until it succeeds, so
+    // a retried or skipped record leaves no pairs behind
+    const holdsPairs = () => maxRetries > 0 || skipsFailures;
+
+    // Runs map for one record, consuming iterables and calling combine, so all of the
+    // record's work counts against its deadline. Returns the pairs left to send, if any.
+    // Pairs emitted by an attempt are only returned with it, when pairs are held.
+    const mapRecord = async (item, meta) => {
+        const held = [];
+        // The global emit is looked up on each call, not kept, so a record that never
+        // settles doesn't keep the engine's result channel open
+        const emitPair = holdsPairs()
+            ? (key, value) => { held.push([key, value]); }
+            : (key, value) => emit(key, value);
+        let pairs = await map(item, meta, emitPair);
+        if (pairs != null && !Array.isArray(pairs) && isIterable(pairs)) {
+            if (typeof combine !== 'function' && !holdsPairs()) {
+                for await (const [key, value] of pairs) {
+                    await emitPair(key, value);
+                }
+                return null;
+            }
+            pairs = await Array.fromAsync(pairs);
+        }
+        if (pairs != null && typeof combine === 'function') {
+            pairs = await combine(pairs);
+        }
+        // Emitted pairs skip combine
+        if (held.length > 0) return pairs == null ? held : held.concat(pairs);
+        return pairs;
+    };
+
+    const runMapWorker = async (concurrency) => {
+        if (typeof map !== 'function') {
+            throw new Error('map function is not defined');
+        }
+        // The global emit can't tell which record calls it, so it only streams when
+        // pairs aren't held
+        if (holdsPairs()) {
+            globalThis.emit = () => {
+                throw new Error('emit() is passed to map as its third argument when failed records are retried or skipped');
+            };
+        }
+        const tick = async () => {
+            for (let finished = false; ; finished = true) {
+                const next = await nextMapItem(finished);
+                if (next === undefined) return;
+                const [item, meta] = next;
+                try {
+                    const pairs = await withRetries(() => withTimeout(() => mapRecord(item, meta)));
+                    if (pairs != null) await sendMapResults(pairs);
+                } catch (error) {
+                    const at = meta.line !== undefined ? `:${meta.line}` : `@${meta.offset}`;
+                    reportFailure('map', meta.file + at, { input: item, meta }, error);
+                }
+            }
+        };
+        await Promise.all(Array.from({length: concurrency}, tick));
+        await flushEmitted();
+    };
+
+    const flatReduce = async (batch) => {
+        if (typeof reduce !== 'function') {
+            throw new Error('Reduce function is not defined');
+        }
+
+        const results = await Promise.all(
+            batch.map(async ([key, values]) => {
+                try {
+                    const reduced = await withRetries(() => withTimeout(() => reduce(key, values)));
+                    return [key, reduced];
+                } catch (error) {
+                    reportFailure('reduce', key, { key, values }, error);
+                }
+            })
+        );
+
+        return results.filter((result) => result !== undefined);
+    };
+"#;
+
+// Defines the `params` global as a deeply frozen object. The global itself stays
+// configurable and writable, so a script may still declare its own `params`.
+const DEFINE_PARAMS: &str = r#"
+    (params) => {
+        const freeze = (value) => {
+            if (value !== null && typeof value === 'object') {
+                Object.values(value).forEach(freeze);
+                Object.freeze(value);
+            }
+            return value;
+        };
+        Object.defineProperty(globalThis, 'params', {
+            value: freeze(params),
+            enumerable: true,
+            configurable: true,
+            writable: true,
+        });
+    }
+"#;
+
+// Replaces `Math.random`, `crypto.getRandomValues` and `crypto.randomUUID` with a seeded
+// mulberry32 generator, and stops the clock at `now`
+const DEFINE_DETERMINISM: &str = r#"
+    (seed, now) => {
+        let state = seed;
+        const next = () => {
+            state = (state + 0x6d2b79f5) >>> 0;
+            let t = Math.imul(state ^ (state >>> 15), state | 1);
+            t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+            return (t ^ (t >>> 14)) >>> 0;
+        };
+        Math.random = () => next() / 4294967296;
+        if (globalThis.crypto) {
+            crypto.getRandomValues = (array) => {
+                const bytes = new Uint8Array(array.buffer, array.byteOffset, array.byteLength);
+                for (let i = 0; i < bytes.length; i++) bytes[i] = next() & 0xff;
+                return array;
+            };
+            crypto.randomUUID = () => {
+                const bytes = crypto.getRandomValues(new Uint8Array(16));
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                const hex = Array.from(bytes, (b) => b.toString(16).padStart(2, '0')).join('');
+                return [hex.slice(0, 8), hex.slice(8, 12), hex.slice(12, 16), hex.slice(16, 20), hex.slice(20)].join('-');
+            };
+        }
+        if (globalThis.performance) {
+            performance.now = () => 0;
+        }
+        const RealDate = Date;
+        RealDate.now = () => now;
+        globalThis.Date = new Proxy(RealDate, {
+            construct: (target, args, newTarget) =>
+                Reflect.construct(target, args.length ? args : [now], newTarget),
+            apply: () => new RealDate(now).toString(),
+        });
+    }
+"#;
+
+// Functions a module script can export for the engine to call
+const SCRIPT_EXPORTS: [&str; 7] = ["map", "combine", "reduce", "sort", "test", "setup", "teardown"];
+
+/// User script, evaluated either as a classic script or as an ES module
+#[derive(Debug, Clone, Hash)]
+pub enum Script {
+    /// Script source and the file name reported in stack traces
+    Classic { name: String, source: String, bytecode: Precompiled },
+    /// Module source and the path its imports are resolved against
+    Module { path: String, source: String, bytecode: Precompiled },
+    /// Script that was already compiled to bytecode
+    Compiled(CompiledScript),
+}
+
+/// Bytecode of a script source that was compiled while reading it, reused by
+/// [`Script::compile`]. It's left out of the hash, which keys the bytecode cache.
+#[derive(Debug, Clone, Default)]
+pub struct Precompiled(Option<Arc<[u8]>>);
+
+impl Hash for Precompiled {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+/// Whether a script file should be loaded as an ES module: either it has an `.mjs`
+/// extension, or it only parses as a module, like scripts with `import` or `export`
+/// declarations. Scripts that parse as neither are classic, to report their syntax errors.
+/// Modules are compiled with `module_path`, which their imports are resolved against, and
+/// the bytecode compiled along the way is returned to be reused.
+pub fn detect_module(path: &str, module_path: &str, source: &str) -> (bool, Precompiled) {
+    if path.ends_with(".mjs") {
+        return (true, Precompiled::default());
+    }
+    match compile(path, source, false) {
+        Ok(bytecode) => (false, Precompiled(Some(bytecode.into()))),
+        Err(_) => match compile(module_path, source, true) {
+            Ok(bytecode) => (true, Precompiled(Some(bytecode.into()))),
+            Err(_) => (false, Precompiled::default()),
+        },
+    }
+}
+
+/// Whether a script file is written in TypeScript, judging by its extension
+pub fn is_typescript(path: &str) -> bool {
+    [".ts", ".mts", ".cts"].iter().any(|ext| path.ends_with(ext))
+}
+
+/// Strip the type annotations from a TypeScript script. Types are replaced with
+/// whitespace, so line and column numbers in errors match the original file.
+/// Syntax errors are printed to stderr with the offending source lines.
+pub fn strip_types(path: &str, source: String) -> Result<String> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let handler = Handler::with_emitter_writer(Box::new(std::io::stderr()), Some(cm.clone()));
+    let options = swc_ts_fast_strip::Options {
+        filename: Some(path.into()),
+        ..Default::default()
+    };
+    let output = GLOBALS.set(&Default::default(), || {
+        HANDLER.set(&handler, || swc_ts_fast_strip::operate(&cm, &handler, source, options))
+    });
+    output
+        .map(|output| output.code)
+        .map_err(|e| anyhow::anyhow!("Failed to strip types from {}: {}", path, e))
+}
+
+/// Script compiled to QuickJS bytecode once, and loaded into every worker VM
+#[derive(Debug, Clone, Hash)]
+pub struct CompiledScript {
+    prelude: Arc<[u8]>,
+    script: Arc<[u8]>,
+    module: bool,
+}
+
+/// On-disk format of a compiled script, written by `pulsar compile` and the bytecode cache
+#[derive(Serialize, Deserialize)]
+struct BytecodeFile {
+    version: String,
+    /// QuickJS build the bytecode was compiled for, see [`engine_id`]
+    engine: String,
+    module: bool,
+    bytecode: Vec<u8>,
+}
+
+/// Identifies the QuickJS build bytecode is written for. `JS_ReadObject` trusts its input,
+/// so bytecode is only read back by a build with the same QuickJS version and the same
+/// prelude bytecode, which changes along with the opcodes and the bytecode format.
+fn engine_id(prelude: &[u8]) -> String {
+    // SAFETY: JS_GetVersion returns a static NUL terminated string
+    let quickjs = unsafe { CStr::from_ptr(qjs::JS_GetVersion()) };
+    let mut hasher = DefaultHasher::new();
+    prelude.hash(&mut hasher);
+    format!("quickjs {} {:016x}", quickjs.to_string_lossy(), hasher.finish())
+}
+
+/// Whether a script file holds precompiled bytecode rather than source
+pub fn is_bytecode(path: &str) -> bool {
+    path.ends_with(".pbc")
+}
+
+impl Script {
+    /// Compile the script to bytecode. With a cache directory, the bytecode is stored
+    /// there keyed by a hash of the script and reused as long as the script is unchanged.
+    pub fn compile(&self, cache_dir: Option<&Path>) -> Result<CompiledScript> {
+        let (name, source, module, bytecode) = match self {
+            Script::Classic { name, source, bytecode } => (name, source, false, bytecode),
+            Script::Module { path, source, bytecode } => (path, source, true, bytecode),
+            Script::Compiled(compiled) => return Ok(compiled.clone()),
+        };
+        let prelude = compile_prelude()?;
+        let cache_file = cache_dir.map(|dir| {
+            let mut hasher = DefaultHasher::new();
+            (env!("CARGO_PKG_VERSION"), engine_id(&prelude), self).hash(&mut hasher);
+            dir.join(format!("{:016x}.pbc", hasher.finish()))
+        });
+        if let Some(cache_file) = cache_file.as_ref().filter(|file| file.exists()) {
+            debug!("Loading cached bytecode from {}", cache_file.display());
+            match CompiledScript::read_with_prelude(cache_file, prelude.clone()) {
+                Ok(compiled) => return Ok(compiled),
+                Err(e) => debug!("Recompiling unusable cached bytecode: {}", e),
+            }
+        }
+
+        let compiled = CompiledScript {
+            prelude,
+            script: match &bytecode.0 {
+                Some(bytecode) => bytecode.clone(),
+                None => compile(name, source, module)?.into(),
+            },
+            module,
+        };
+        if let Some(cache_file) = cache_file {
+            std::fs::create_dir_all(cache_file.parent().unwrap_or(Path::new(".")))?;
+            compiled.write(&cache_file)?;
+        }
+        Ok(compiled)
+    }
+}
+
+impl CompiledScript {
+    /// Read a compiled script written by [`CompiledScript::write`]
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::read_with_prelude(path, compile_prelude()?)
+    }
+
+    fn read_with_prelude(path: &Path, prelude: Arc<[u8]>) -> Result<Self> {
+        let file: BytecodeFile = std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(bincode::deserialize(&bytes)?))
+            .map_err(|e| anyhow::anyhow!("Failed to read bytecode file {}: {}", path.display(), e))?;
+        if file.version != env!("CARGO_PKG_VERSION") || file.engine != engine_id(&prelude) {
+            return Err(anyhow::anyhow!(
+                "Bytecode file {} was compiled by pulsar {} ({}), recompile it with pulsar {} ({})",
+                path.display(),
+                file.version,
+                file.engine,
+                env!("CARGO_PKG_VERSION"),
+                engine_id(&prelude)
+            ));
+        }
+        Ok(CompiledScript {
+            prelude,
+            script: file.bytecode.into(),
+            module: file.module,
+        })
+    }
+
+    /// Write the script bytecode to a file. The file is written next to `path` and renamed
+    /// into place, so concurrent jobs sharing a cache never read a partial file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = BytecodeFile {
+            version: env!("CARGO_PKG_VERSION").into(),
+            engine: engine_id(&self.prelude),
+            module: self.module,
+            bytecode: self.script.to_vec(),
+        };
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        std::fs::write(&tmp, bincode::serialize(&file)?)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&tmp);
+                anyhow::anyhow!("Failed to write bytecode file {}: {}", path.display(), e)
+            })
+    }
+
+    /// File imports of a module script, which are resolved against the paths of the
+    /// machine it was compiled on
+    pub fn file_imports(&self) -> Result<Vec<String>> {
+        if !self.module {
+            return Ok(Vec::new());
+        }
+        let imports = Arc::new(Mutex::new(Vec::new()));
+        let runtime = rquickjs::Runtime::new()?;
+        let recorder = DeferredImports(Some(imports.clone()));
+        runtime.set_loader(recorder, DeferredImports(None));
+        let context = rquickjs::Context::full(&runtime)?;
+        context.with(|ctx| {
+            // SAFETY: see `eval_script_bytecode`. Resolving the module loads its imports
+            // without evaluating it.
+            let resolved = unsafe {
+                let ctx_ptr = ctx.as_raw().as_ptr();
+                let module = qjs::JS_ReadObject(
+                    ctx_ptr,
+                    self.script.as_ptr(),
+                    self.script.len() as _,
+                    qjs::JS_READ_OBJ_BYTECODE as i32,
+                );
+                if qjs::JS_IsException(module) || qjs::JS_ResolveModule(ctx_ptr, module) < 0 {
+                    Err(rquickjs::Error::Exception)
+                } else {
+                    drop(rquickjs::Value::from_raw(ctx.clone(), module));
+                    Ok(())
+                }
+            };
+            resolved.catch(&ctx).map_err(|e| anyhow::anyhow!("{}", e))
+        })?;
+        let mut imports: Vec<String> = std::mem::take(&mut imports.lock().unwrap());
+        imports.retain(|name| name.starts_with("./") || name.starts_with("../") || name.starts_with('/'));
+        imports.sort();
+        imports.dedup();
+        Ok(imports)
+    }
+}
+
+/// Compile the engine prelude that is evaluated before every script
+fn compile_prelude() -> Result<Arc<[u8]>> {
+    let prelude = format!("{}\n{}", LIFECYCLE_HOOKS, MAP_REDUCE_WRAPPER);
+    Ok(compile("prelude", &prelude, false)?.into())
+}
+
+/// Module loader that stands in an empty module for every import while compiling,
+/// as imports are only resolved by the worker VMs once the bytecode is evaluated.
+/// Optionally records the names imported.
+struct DeferredImports(Option<Arc<Mutex<Vec<String>>>>);
+
+impl Resolver for DeferredImports {
+    fn resolve(&mut self, _ctx: &rquickjs::Ctx<'_>, _base: &str, name: &str) -> rquickjs::Result<String> {
+        if let Some(imports) = &self.0 {
+            imports.lock().unwrap().push(name.into());
+        }
+        Ok(name.into())
+    }
+}
+
+impl Loader for DeferredImports {
+    fn load<'js>(&mut self, ctx: &rquickjs::Ctx<'js>, name: &str) -> rquickjs::Result<Module<'js>> {
+        Module::declare(ctx.clone(), name, "")
+    }
+}
+
+/// Compile a classic script or a module to bytecode in a throwaway context
+fn compile(name: &str, source: &str, module: bool) -> Result<Vec<u8>> {
+    let runtime = rquickjs::Runtime::new()?;
+    runtime.set_loader(DeferredImports(None), DeferredImports(None));
+    let context = rquickjs::Context::full(&runtime)?;
+    context.with(|ctx| {
+        let bytecode = if module {
+            Module::declare(ctx.clone(), name, source)
+                .and_then(|module| module.write(WriteOptions::default()))
+        } else {
+            compile_script(&ctx, name, source)
+        };
+        bytecode.catch(&ctx).map_err(|e| anyhow::anyhow!("{}", e))
+    })
+}
+
+fn compile_script(ctx: &rquickjs::Ctx<'_>, name: &str, source: &str) -> rquickjs::Result<Vec<u8>> {
+    let name = CString::new(name)?;
+    let source = CString::new(source)?;
+    // Strict like scripts evaluated with `ctx.eval`, whose default options are strict
+    let flags =
+        qjs::JS_EVAL_TYPE_GLOBAL | qjs::JS_EVAL_FLAG_STRICT | qjs::JS_EVAL_FLAG_COMPILE_ONLY;
+    // SAFETY: both strings are NUL terminated, and the compiled function is owned by
+    // `function`, which frees it when dropped. The buffer returned by JS_WriteObject is
+    // copied before being freed.
+    unsafe {
+        let ctx_ptr = ctx.as_raw().as_ptr();
+        let value = qjs::JS_Eval(
+            ctx_ptr,
+            source.as_ptr(),
+            source.as_bytes().len() as _,
+            name.as_ptr(),
+            flags as i32,
+        );
+        if qjs::JS_IsException(value) {
+            return Err(rquickjs::Error::Exception);
+        }
+        let function = rquickjs::Value::from_raw(ctx.clone(), value);
+        let mut len = 0;
+        let buf = qjs::JS_WriteObject(
+            ctx_ptr,
+            &mut len,
+            function.as_raw(),
+            qjs::JS_WRITE_OBJ_BYTECODE as i32,
+        );
+        if buf.is_null() {
+            return Err(rquickjs::Error::Exception);
+        }
+        let bytecode = std::slice::from_raw_parts(buf, len as usize).to_vec();
+        qjs::js_free(ctx_ptr, buf.cast());
+        Ok(bytecode)
+    }
+}
+
+/// Evaluate a classic script compiled by [`compile_script`]
+fn eval_script_bytecode(ctx: &rquickjs::Ctx<'_>, bytecode: &[u8]) -> rquickjs::Result<()> {
+    // SAFETY: the bytecode was produced by `compile_script`, in this process or read from
+    // a file whose engine id matches this build. QuickJS doesn't validate bytecode, so a
+    // corrupted or crafted file is not safe to load; bytecode files have to be trusted.
+    // JS_EvalFunction takes ownership of the function object.
+    unsafe {
+        let ctx_ptr = ctx.as_raw().as_ptr();
+        let function = qjs::JS_ReadObject(
+            ctx_ptr,
+            bytecode.as_ptr(),
+            bytecode.len() as _,
+            qjs::JS_READ_OBJ_BYTECODE as i32,
+        );
+        if qjs::JS_IsException(function) {
+            return Err(rquickjs::Error::Exception);
+        }
+        let result = qjs::JS_EvalFunction(ctx_ptr, function);
+        if qjs::JS_IsException(result) {
+            return Err(rquickjs::Error::Exception);
+        }
+        drop(rquickjs::Value::from_raw(ctx.clone(), result));
+        Ok(())
+    }
+}
+
+/// Evaluate a module compiled by [`compile`], resolving its imports with the VM's loader.
+/// Returns the promise of the module evaluation and the module namespace.
+fn eval_module_bytecode<'js>(
+    ctx: &rquickjs::Ctx<'js>,
+    bytecode: &[u8],
+) -> rquickjs::Result<(Promise<'js>, rquickjs::Object<'js>)> {
+    // SAFETY: see `eval_script_bytecode`. The module definition stays alive in the
+    // context's module list after JS_EvalFunction consumes the module value.
+    unsafe {
+        let ctx_ptr = ctx.as_raw().as_ptr();
+        let module = qjs::JS_ReadObject(
+            ctx_ptr,
+            bytecode.as_ptr(),
+            bytecode.len() as _,
+            qjs::JS_READ_OBJ_BYTECODE as i32,
+        );
+        if qjs::JS_IsException(module) || qjs::JS_ResolveModule(ctx_ptr, module) < 0 {
+            return Err(rquickjs::Error::Exception);
+        }
+        let module_def = qjs::JS_VALUE_GET_PTR(module).cast::<qjs::JSModuleDef>();
+        let result = qjs::JS_EvalFunction(ctx_ptr, module);
+        if qjs::JS_IsException(result) {
+            return Err(rquickjs::Error::Exception);
+        }
+        let promise = rquickjs::Value::from_raw(ctx.clone(), result);
+        let namespace = qjs::JS_GetModuleNamespace(ctx_ptr, module_def);
+        if qjs::JS_IsException(namespace) {
+            return Err(rquickjs::Error::Exception);
+        }
+        let namespace = rquickjs::Value::from_raw(ctx.clone(), namespace);
+        Ok((promise.get()?, namespace.get()?))
+    }
+}
+
+/// Evaluate the compiled engine prelude and user script. Module scripts are evaluated
+/// on their own and their exported functions are exposed as globals for the prelude.
+async fn load_script(vm: &Vm, script: &CompiledScript, params: Value) -> Result<(), String> {
+    async_with!(vm.ctx => |ctx| {
+        define_params(&ctx, params).catch(&ctx).map_err(|e| e.to_string())?;
+        // Top-level declarations of the prelude and a classic script share the global scope
+        eval_script_bytecode(&ctx, &script.prelude).catch(&ctx).map_err(|e| e.to_string())?;
+        if !script.module {
+            eval_script_bytecode(&ctx, &script.script).catch(&ctx).map_err(|e| e.to_string())?;
+        } else {
+            let (promise, namespace) = eval_module_bytecode(&ctx, &script.script)
+                .catch(&ctx)
+                .map_err(|e| e.to_string())?;
+            let () = promise
+                .into_future()
+                .await
+                .catch(&ctx)
+                .map_err(|e| e.to_string())?;
+            for name in SCRIPT_EXPORTS {
+                let export: rquickjs::Value = namespace.get(name).map_err(|e| e.to_string())?;
+                if !export.is_undefined() {
+                    ctx.globals().set(name, export).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Expose the job parameters to the script as the `params` global
+fn define_params(ctx: &llrt_core::Ctx<'_>, params: Value) -> rquickjs::Result<()> {
+    let define: Function = ctx.eval(DEFINE_PARAMS)?;
+    define.call((params,))
+}
+
+// Number of pairs emitted with `emit()` that are buffered before being sent
+const EMIT_BATCH_SIZE: usize = 1024;
+
+/// Pairs passed to `emit()` in a worker that weren't sent yet
+#[derive(Default)]
+struct Emitted {
+    /// Pairs of the batch being filled
+    buffer: Vec<KeyValue>,
+    /// Pairs either buffered or in full batches waiting to be sent
+    pending: usize,
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
@@ -18,12 +633,13 @@ pub enum Value {
     Int(i64),
     Float(f64),
     String(String),
+    Bytes(Vec<u8>),
     Array(Vec<Value>),
     Object(HashMap<String, Value>),
 }
 
 // Key-value pair for MapReduce operations
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyValue {
     pub key: String,
     pub value: Value,
@@ -33,6 +649,7 @@ impl<'js> llrt_core::IntoJs<'js> for Value {
     fn into_js(self, ctx: &llrt_core::Ctx<'js>) -> rquickjs::Result<llrt_core::Value<'js>> {
         match self {
             Value::String(s) => s.into_js(ctx),
+            Value::Bytes(b) => Ok(rquickjs::TypedArray::<u8>::new(ctx.clone(), b)?.into_value()),
             Value::Int(i) => i.into_js(ctx),
             Value::Float(f) => f.into_js(ctx),
             Value::Bool(b) => b.into_js(ctx),
@@ -69,6 +686,8 @@ impl<'js> llrt_core::FromJs<'js> for Value {
             Ok(Value::Bool(value.as_bool().unwrap_or(false)))
         } else if value.is_null() {
             Ok(Value::Null)
+        } else if let Some(bytes) = value.as_object().and_then(|o| o.as_typed_array::<u8>()) {
+            Ok(Value::Bytes(bytes.as_bytes().unwrap_or_default().to_vec()))
         } else if value.is_array() {
             let js_array = value.as_array().unwrap();
             let mut vec = Vec::new();
@@ -101,6 +720,33 @@ impl<'js> llrt_core::FromJs<'js> for Value {
     }
 }
 
+// A single input record along with where it was read from.
+// Records read from a byte-range split carry their byte offset instead of a line number.
+#[derive(Debug, Clone)]
+pub struct MapItem {
+    pub value: Value,
+    pub file: Arc<str>,
+    pub line: Option<usize>,
+    pub offset: Option<u64>,
+}
+
+impl<'js> llrt_core::IntoJs<'js> for MapItem {
+    fn into_js(self, ctx: &llrt_core::Ctx<'js>) -> rquickjs::Result<llrt_core::Value<'js>> {
+        let meta = rquickjs::Object::new(ctx.clone())?;
+        meta.set("file", &*self.file)?;
+        if let Some(line) = self.line {
+            meta.set("line", line)?;
+        }
+        if let Some(offset) = self.offset {
+            meta.set("offset", offset)?;
+        }
+        let js_array = rquickjs::Array::new(ctx.clone())?;
+        js_array.set(0, self.value.into_js(ctx)?)?;
+        js_array.set(1, meta)?;
+        Ok(js_array.into())
+    }
+}
+
 impl<'js> llrt_core::FromJs<'js> for KeyValue {
     fn from_js(ctx: &llrt_core::Ctx<'js>, value: llrt_core::Value<'js>) -> rquickjs::Result<Self> {
         if value.is_array() {
@@ -134,6 +780,7 @@ impl ToString for Value {
     fn to_string(&self) -> String {
         match self {
             Value::String(s) => s.clone(),
+            Value::Bytes(b) => String::from_utf8_lossy(b).into_owned(),
             Value::Int(n) => n.to_string(),
             Value::Float(f) => f.to_string(),
             Value::Bool(b) => b.to_string(),
@@ -156,6 +803,7 @@ impl From<&Value> for serde_json::Value {
     fn from(value: &Value) -> Self {
         match value {
             Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::Bytes(b) => serde_json::Value::Array(b.iter().map(|&n| n.into()).collect()),
             Value::Int(n) => serde_json::Value::Number(serde_json::Number::from(*n)),
             Value::Float(n) => serde_json::Number::from_f64(*n)
                 .map(serde_json::Value::Number)
@@ -177,11 +825,31 @@ impl From<&Value> for serde_json::Value {
     }
 }
 
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Float(n.as_f64().unwrap_or(0.0)),
+            },
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(arr) => Value::Array(arr.into_iter().map(Into::into).collect()),
+            serde_json::Value::Object(obj) => {
+                Value::Object(obj.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
 pub enum JobRequest {
     RunMapPhase {
-        item_rx: Receiver<String>,
+        item_rx: Receiver<MapItem>,
         result_tx: mpsc::Sender<Vec<KeyValue>>,
         concurrency: usize,
+        /// Number of records taken from `item_rx` that are still being mapped
+        in_flight: Arc<AtomicUsize>,
         done_tx: oneshot::Sender<Result<()>>,
     },
     Reduce(Vec<(String, Vec<Value>)>, oneshot::Sender<JobResult>),
@@ -214,98 +882,492 @@ pub enum JobResult {
     Error(String),
 }
 
+/// Limits applied to every VM started by `start_vm_worker` and `run_test_file`
+#[derive(Debug, Clone, Default)]
+pub struct VmOptions {
+    /// Maximum bytes the VM's heap may allocate
+    pub memory_limit: Option<usize>,
+    /// Maximum bytes of stack JS code may use
+    pub stack_size: Option<usize>,
+    /// How long `map` or `reduce` may run for a single record
+    pub record_timeout: Option<Duration>,
+    /// When the whole job has to be finished; JS still running after it is interrupted
+    pub job_deadline: Option<Instant>,
+    /// Host modules available in sandbox mode; `None` when not sandboxed
+    pub sandbox: Option<Vec<HostModule>>,
+    /// Seeded `Math.random` and `crypto` randomness and frozen clock for reproducible runs
+    pub deterministic: Option<Deterministic>,
+}
+
+impl VmOptions {
+    /// Options for the VM of worker `idx`, which gets its own random sequence
+    pub fn for_worker(&self, idx: usize) -> Self {
+        let mut options = self.clone();
+        if let Some(deterministic) = &mut options.deterministic {
+            deterministic.seed ^= (idx as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Deterministic {
+    /// Seed of `Math.random`
+    pub seed: u64,
+    /// What `Date.now()` and `new Date()` return, in milliseconds since the epoch
+    pub now: i64,
+}
+
+impl Deterministic {
+    /// 32-bit generator state for the seed, mixed with splitmix64 so nearby seeds differ
+    fn state(&self) -> u32 {
+        let mut z = self.seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as u32
+    }
+}
+
+/// Builtin modules that reach outside the VM, left out in sandbox mode unless allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HostModule {
+    #[value(name = "child_process")]
+    ChildProcess,
+    Dns,
+    /// The `fetch` global
+    Fetch,
+    /// `fs` and `fs/promises`
+    Fs,
+    Https,
+    /// The `require` global and the `module` builtin, which load files
+    Module,
+    Net,
+    Os,
+    /// The `process` module and global
+    Process,
+    Tty,
+}
+
+impl fmt::Display for HostModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostModule::ChildProcess => write!(f, "child_process"),
+            HostModule::Dns => write!(f, "dns"),
+            HostModule::Fetch => write!(f, "fetch"),
+            HostModule::Fs => write!(f, "fs"),
+            HostModule::Https => write!(f, "https"),
+            HostModule::Module => write!(f, "module"),
+            HostModule::Net => write!(f, "net"),
+            HostModule::Os => write!(f, "os"),
+            HostModule::Process => write!(f, "process"),
+            HostModule::Tty => write!(f, "tty"),
+        }
+    }
+}
+
+/// Modules of a sandboxed VM: the pure builtins plus the allowed host modules
+fn sandbox_modules(allowed: &[HostModule]) -> ModuleBuilder {
+    use llrt_core::modules::*;
+    let mut builder = ModuleBuilder::new()
+        .with_global(embedded::init)
+        .with_global(llrt_core::builtins_inspect::init)
+        .with_global(module::init)
+        .with_global(abort::init)
+        .with_module(assert::AssertModule)
+        .with_global(async_hooks::init)
+        .with_module(async_hooks::AsyncHooksModule)
+        .with_global(buffer::init)
+        .with_module(buffer::BufferModule)
+        .with_global(console::init)
+        .with_module(console::ConsoleModule)
+        .with_global(crypto::init)
+        .with_module(crypto::CryptoModule)
+        .with_global(events::init)
+        .with_module(events::EventsModule)
+        .with_global(exceptions::init)
+        .with_global(navigator::init)
+        .with_module(path::PathModule)
+        .with_global(perf_hooks::init)
+        .with_module(perf_hooks::PerfHooksModule)
+        .with_global(stream_web::init)
+        .with_module(stream_web::StreamWebModule)
+        .with_module(string_decoder::StringDecoderModule)
+        .with_global(timers::init)
+        .with_module(timers::TimersModule)
+        .with_global(url::init)
+        .with_module(url::UrlModule)
+        .with_global(util::init)
+        .with_module(util::UtilModule)
+        .with_module(zlib::ZlibModule)
+        .with_module(llrt::hex::LlrtHexModule)
+        .with_module(llrt::qjs::LlrtQjsModule)
+        .with_module(llrt::timezone::LlrtTimezoneModule)
+        .with_module(llrt::util::LlrtUtilModule)
+        .with_module(llrt::xml::LlrtXmlModule);
+    for module in allowed {
+        builder = match module {
+            HostModule::ChildProcess => builder.with_module(child_process::ChildProcessModule),
+            HostModule::Dns => builder.with_module(dns::DnsModule),
+            HostModule::Fetch => builder.with_global(fetch::init),
+            HostModule::Fs => builder
+                .with_module(fs::FsPromisesModule)
+                .with_module(fs::FsModule),
+            HostModule::Https => builder.with_module(https::HttpsModule),
+            HostModule::Module => builder.with_module(module::ModuleModule),
+            HostModule::Net => builder.with_module(net::NetModule),
+            HostModule::Os => builder.with_module(os::OsModule),
+            HostModule::Process => builder
+                .with_global(process::init)
+                .with_module(process::ProcessModule),
+            HostModule::Tty => builder.with_module(tty::TtyModule),
+        };
+    }
+    builder
+}
+
+// Stack left to the VM thread on top of the JS stack size, for the runtime and native calls
+const THREAD_STACK_MARGIN: usize = 2 * 1024 * 1024;
+
+/// Thread to run a VM on, with enough stack for the JS stack size
+fn vm_thread(options: &VmOptions) -> thread::Builder {
+    match options.stack_size {
+        Some(stack_size) => thread::Builder::new().stack_size(stack_size + THREAD_STACK_MARGIN),
+        None => thread::Builder::new(),
+    }
+}
+
+/// Create a VM with the sandbox, limits and deterministic mode applied. Allocations past
+/// the memory limit throw an out of memory error and a too deep stack throws a
+/// `RangeError` in the JS code that caused it, so they fail that record only.
+async fn new_vm(options: &VmOptions) -> Result<Vm> {
+    let vm = match &options.sandbox {
+        Some(allowed) => {
+            Vm::from_options(llrt_core::vm::VmOptions {
+                module_builder: sandbox_modules(allowed),
+                ..Default::default()
+            })
+            .await
+        }
+        None => Vm::new().await,
+    };
+    let vm = vm
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+        .context("Failed to create VM")?;
+    if let Some(allowed) = &options.sandbox {
+        // llrt also resolves imports from files and packages; a sandboxed script may only
+        // import the sandbox's builtins
+        let (resolver, loader, _) = sandbox_modules(allowed).build();
+        vm.runtime.set_loader(resolver, loader).await;
+        if !allowed.contains(&HostModule::Module) {
+            async_with!(vm.ctx => |ctx| { ctx.globals().remove("require") })
+                .await
+                .map_err(|e| anyhow::anyhow!(e.to_string()))
+                .context("Failed to set up sandbox")?;
+        }
+    }
+    if let Some(memory_limit) = options.memory_limit {
+        vm.runtime.set_memory_limit(memory_limit).await;
+    }
+    if let Some(stack_size) = options.stack_size {
+        vm.runtime.set_max_stack_size(stack_size).await;
+    }
+    if let Some(deterministic) = options.deterministic {
+        async_with!(vm.ctx => |ctx| {
+            let define: Function = ctx.eval(DEFINE_DETERMINISM)?;
+            define.call::<_, ()>((deterministic.state(), deterministic.now as f64))
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+        .context("Failed to set up deterministic mode")?;
+    }
+    Ok(vm)
+}
+
+// How often a VM's event loop records that it isn't blocked by running JS
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Deadline of a record a VM is running `map` or `reduce` for
+#[derive(Debug)]
+struct RecordDeadline {
+    started: Instant,
+    at: Instant,
+    // Set once the deadline passes with the record still running
+    expired: bool,
+    // Dropped when the record ends, which settles its pending `recordExpired` promise
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Deadlines of the records in flight on a VM, shared with its interrupt handler
+#[derive(Debug)]
+struct Deadlines {
+    next_id: u32,
+    records: HashMap<u32, RecordDeadline>,
+    heartbeat: Instant,
+}
+
+impl Deadlines {
+    /// Whether running JS should be interrupted: the job is past its deadline, or a record
+    /// is past its own deadline and the VM hasn't been back to its event loop since the
+    /// record started, so it's that record's code that keeps it busy. The heartbeat stops
+    /// while JS runs. A record waiting on a promise is failed by `recordExpired` instead.
+    fn interrupt(&mut self, options: &VmOptions) -> bool {
+        let now = Instant::now();
+        if options.job_deadline.is_some_and(|deadline| now >= deadline) {
+            return true;
+        }
+        let blocked = self
+            .records
+            .values()
+            .any(|record| now >= record.at && self.heartbeat <= record.started);
+        if blocked {
+            // The interrupted code stops here, so don't blame records still waiting to run
+            self.heartbeat = now;
+        }
+        blocked
+    }
+}
+
 #[instrument(level = "trace")]
 pub fn start_vm_worker(
-    js_code: String,
+    script: CompiledScript,
+    params: Value,
+    failures: Arc<Failures>,
+    options: VmOptions,
     rx: Receiver<JobRequest>,
     init_tx: oneshot::Sender<Result<()>>,
 ) -> Result<JoinHandle<Result<()>>> {
-    let handle = thread::spawn(move || {
+    let handle = vm_thread(&options).spawn(move || {
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .context("Failed to create Tokio runtime")?;
 
         runtime.block_on(async move {
-            let vm = Vm::new()
-                .await
-                .map_err(|e| anyhow::anyhow!(e.to_string()))
-                .context("Failed to create VM")?;
-
-            let eval_result = vm
-                .ctx
-                .with(|ctx| {
-                    let wrapper = r#"
-                        const runMapWorker = async (concurrency) => {
-                            if (typeof map !== 'function') {
-                                throw new Error('map function is not defined');
-                            }
-                            const tick = async () => {
-                                while (true) {
-                                    const item = await nextMapItem();
-                                    if (item == null) return;
-                                    let pairs = await map(item);
-                                    if (typeof combine === 'function') {
-                                        pairs = await combine(pairs);
-                                    }
-                                    await sendMapResults(pairs);
-                                }
-                            };
-                            await Promise.all(Array.from({length: concurrency}, tick));
-                        };
+            let vm = new_vm(&options).await?;
 
-                        const flatReduce = async (batch) => {
-                            if (typeof reduce !== 'function') {
-                                throw new Error('Reduce function is not defined');
-                            }
-
-                            const results = await Promise.all(
-                                batch.map(async ([key, values]) => {
-                                    const reduced = await reduce(key, values);
-                                    return [key, reduced];
-                                })
-                            );
-
-                            return results;
-                        };
-                    "#;
+            let deadlines = Arc::new(Mutex::new(Deadlines {
+                next_id: 0,
+                records: HashMap::new(),
+                heartbeat: Instant::now(),
+            }));
+            if options.record_timeout.is_some() {
+                let deadlines = deadlines.clone();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        deadlines.lock().unwrap().heartbeat = Instant::now();
+                    }
+                });
+            }
+            if options.record_timeout.is_some() || options.job_deadline.is_some() {
+                let (deadlines, options) = (deadlines.clone(), options.clone());
+                vm.runtime
+                    .set_interrupt_handler(Some(Box::new(move || {
+                        deadlines.lock().unwrap().interrupt(&options)
+                    })))
+                    .await;
+            }
 
-                    ctx.eval::<(), _>(format!("{}\n{}", wrapper, js_code))
-                        .catch(&ctx)
-                        .map_err(|e| e.to_string())
-                })
-                .await;
+            let eval_result = async {
+                load_script(&vm, &script, params).await?;
+                define_failure_reporter(&vm, failures).await?;
+                define_record_deadlines(&vm, deadlines, options.record_timeout).await
+            }
+            .await;
             if let Err(e) = eval_result {
-                error!("Error loading JS code: {}", e);
                 let _ = init_tx.send(Err(anyhow::anyhow!("Error loading JS code: {}", e)));
                 return Err(anyhow::anyhow!("Error loading JS code: {}", e));
             }
 
+            if let Err(e) = run_hook(&vm, "runSetup").await {
+                let _ = init_tx.send(Err(anyhow::anyhow!("Error in setup: {}", e)));
+                return Err(anyhow::anyhow!("Error in setup: {}", e));
+            }
+
             let _ = init_tx.send(Ok(()));
 
-            while let Ok(job) = rx.recv_async().await {
-                handle_job(&vm, job).await;
+            // Past the job deadline the worker stops, even in the middle of a job, so the
+            // job can end while JS waits on a promise that never settles
+            let deadline = options.job_deadline.map(tokio::time::Instant::from_std);
+            loop {
+                let next = async {
+                    let job = rx.recv_async().await.ok()?;
+                    handle_job(&vm, job).await;
+                    Some(())
+                };
+                let handled = match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, next)
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Job deadline passed"))?,
+                    None => next.await,
+                };
+                if handled.is_none() {
+                    break;
+                }
             }
+            let teardown = run_hook(&vm, "runTeardown").await;
             let _ = vm.idle().await;
 
-            Ok(())
+            teardown.map_err(|e| anyhow::anyhow!("Error in teardown: {}", e))
         })
-    });
+    })
+    .context("Failed to start JS VM thread")?;
 
     Ok(handle)
 }
 
+/// Define the `maxRetries`, `skipsFailures` and `recordFailure` globals used by the map and
+/// reduce drivers. `recordFailure` returns false when the error policy stops the job, so
+/// the driver rethrows the error.
+async fn define_failure_reporter(vm: &Vm, failures: Arc<Failures>) -> Result<(), String> {
+    async_with!(vm.ctx => |ctx| {
+        ctx.globals().set("maxRetries", failures.retries()).map_err(|e| e.to_string())?;
+        ctx.globals()
+            .set("skipsFailures", failures.policy() != OnError::Fail)
+            .map_err(|e| e.to_string())?;
+        ctx.globals().set(
+            "recordFailure",
+            Function::new(ctx.clone(), move |ctx: llrt_core::Ctx<'_>, phase: String, label: String, error: String, details: Value| {
+                if failures.policy() == OnError::Fail {
+                    return Ok(false);
+                }
+                let phase = if phase == "map" { Phase::Map } else { Phase::Reduce };
+                failures
+                    .record(phase, 1, &error, Some(label), serde_json::Value::from(&details))
+                    .map_err(|e| rquickjs::Exception::throw_message(&ctx, &e.to_string()))?;
+                Ok::<_, rquickjs::Error>(true)
+            }),
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// Define the `recordTimeout`, `startRecord`, `endRecord` and `recordExpired` globals the
+/// map and reduce drivers use to put a deadline on each record. `recordTimeout` is 0
+/// when records have no deadline.
+async fn define_record_deadlines(
+    vm: &Vm,
+    deadlines: Arc<Mutex<Deadlines>>,
+    record_timeout: Option<Duration>,
+) -> Result<(), String> {
+    async_with!(vm.ctx => |ctx| {
+        let globals = ctx.globals();
+        let timeout = record_timeout.unwrap_or_default();
+        globals.set("recordTimeout", timeout.as_secs_f64()).map_err(|e| e.to_string())?;
+        let records = deadlines.clone();
+        globals.set(
+            "startRecord",
+            Function::new(ctx.clone(), move || {
+                let mut deadlines = records.lock().unwrap();
+                let id = deadlines.next_id;
+                deadlines.next_id = id.wrapping_add(1);
+                let started = Instant::now();
+                let deadline = RecordDeadline {
+                    started,
+                    at: started + timeout,
+                    expired: false,
+                    cancel: None,
+                };
+                deadlines.records.insert(id, deadline);
+                id
+            }),
+        ).map_err(|e| e.to_string())?;
+        // Returns whether the record's deadline passed before it ended
+        let records = deadlines.clone();
+        globals.set(
+            "endRecord",
+            Function::new(ctx.clone(), move |id: u32| {
+                records
+                    .lock()
+                    .unwrap()
+                    .records
+                    .remove(&id)
+                    .is_some_and(|record| record.expired)
+            }),
+        ).map_err(|e| e.to_string())?;
+        // Resolves once the record's deadline passes, or with false when it ends before
+        let records = deadlines;
+        globals.set(
+            "recordExpired",
+            Function::new(ctx.clone(), Async(move |id: u32| {
+                let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+                let at = records.lock().unwrap().records.get_mut(&id).map(|record| {
+                    record.cancel = Some(cancel_tx);
+                    record.at
+                });
+                let records = records.clone();
+                async move {
+                    let Some(at) = at else { return false };
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(at.into()) => {
+                            if let Some(record) = records.lock().unwrap().records.get_mut(&id) {
+                                record.expired = true;
+                            }
+                            true
+                        }
+                        _ = cancel_rx => false,
+                    }
+                }
+            })),
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
+    .await
+}
+
+/// Call one of the lifecycle hook wrappers and wait for it to settle
+async fn run_hook(vm: &Vm, name: &'static str) -> Result<(), String> {
+    async_with!(vm.ctx => |ctx| {
+        let hook_fn = ctx.globals()
+            .get::<_, Function>(name)
+            .or_else(|_| ctx.eval(name))
+            .map_err(|e| format!("{} not found: {}", name, e))?;
+        let promise: Promise = hook_fn
+            .call(())
+            .catch(&ctx)
+            .map_err(|e| e.to_string())?;
+        let () = promise
+            .into_future()
+            .await
+            .catch(&ctx)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    })
+    .await
+}
+
+/// Spells out the signature of the `emit` closure, whose returned promise borrows the
+/// context it's called with, which closure inference can't express
+fn emit_fn<F>(f: F) -> F
+where
+    F: for<'js> Fn(llrt_core::Ctx<'js>, String, Value) -> rquickjs::Result<Promise<'js>>,
+{
+    f
+}
+
 #[instrument(level = "trace", skip(vm))]
 async fn handle_job(vm: &Vm, job: JobRequest) {
     match job {
-        JobRequest::RunMapPhase { item_rx, result_tx, concurrency, done_tx } => {
+        JobRequest::RunMapPhase { item_rx, result_tx, concurrency, in_flight, done_tx } => {
             let result = async_with!(vm.ctx => |ctx| {
                 let rx = item_rx.clone();
+                // Takes whether the caller finished a record, to keep count of those in flight
                 ctx.globals().set(
                     "nextMapItem",
-                    Function::new(ctx.clone(), Async(move || {
-                        let rx = rx.clone();
-                        async move { rx.recv_async().await.ok() }
+                    Function::new(ctx.clone(), Async(move |finished: bool| {
+                        if finished {
+                            in_flight.fetch_sub(1, Ordering::Relaxed);
+                        }
+                        let (rx, in_flight) = (rx.clone(), in_flight.clone());
+                        async move {
+                            let item = rx.recv_async().await.ok();
+                            if item.is_some() {
+                                in_flight.fetch_add(1, Ordering::Relaxed);
+                            }
+                            item
+                        }
                     })),
                 ).map_err(|e| e.to_string())?;
                 let tx = result_tx.clone();
@@ -316,6 +1378,58 @@ async fn handle_job(vm: &Vm, job: JobRequest) {
                         async move { let _ = tx.send(kvs).await; }
                     })),
                 ).map_err(|e| e.to_string())?;
+                // emit() buffers pairs and sends them in batches, one at a time. The returned
+                // promise resolves once the caller's turn to send came, so awaiting it applies
+                // backpressure. Awaited calls leave at most a batch being sent, a batch being
+                // filled and a pair per concurrent record unsent, so more pending pairs than
+                // that mean emit() isn't awaited, and it throws instead of buffering them.
+                let emitted = Arc::new(Mutex::new(Emitted::default()));
+                let sending = Arc::new(tokio::sync::Mutex::new(()));
+                let max_pending = 2 * EMIT_BATCH_SIZE + concurrency;
+                let (tx, state, turn) = (result_tx.clone(), emitted.clone(), sending.clone());
+                ctx.globals().set(
+                    "emit",
+                    Function::new(ctx.clone(), emit_fn(move |ctx, key, value| {
+                        let batch = {
+                            let mut emitted = state.lock().unwrap();
+                            if emitted.pending >= max_pending {
+                                return Err(rquickjs::Exception::throw_message(
+                                    &ctx,
+                                    &format!("{} emitted pairs are waiting to be sent, await emit() so map waits for them", emitted.pending),
+                                ));
+                            }
+                            emitted.pending += 1;
+                            emitted.buffer.push(KeyValue { key, value });
+                            (emitted.buffer.len() >= EMIT_BATCH_SIZE)
+                                .then(|| std::mem::take(&mut emitted.buffer))
+                        };
+                        let (tx, state, turn) = (tx.clone(), state.clone(), turn.clone());
+                        Promise::wrap_future(&ctx, async move {
+                            let _turn = turn.lock().await;
+                            if let Some(batch) = batch {
+                                let sent = batch.len();
+                                let _ = tx.send(batch).await;
+                                state.lock().unwrap().pending -= sent;
+                            }
+                        })
+                    })),
+                ).map_err(|e| e.to_string())?;
+                let (tx, state, turn) = (result_tx.clone(), emitted.clone(), sending.clone());
+                ctx.globals().set(
+                    "flushEmitted",
+                    Function::new(ctx.clone(), Async(move || {
+                        let (tx, state, turn) = (tx.clone(), state.clone(), turn.clone());
+                        async move {
+                            let _turn = turn.lock().await;
+                            let batch = std::mem::take(&mut state.lock().unwrap().buffer);
+                            if !batch.is_empty() {
+                                let sent = batch.len();
+                                let _ = tx.send(batch).await;
+                                state.lock().unwrap().pending -= sent;
+                            }
+                        }
+                    })),
+                ).map_err(|e| e.to_string())?;
                 let run_fn = ctx.globals()
                     .get::<_, Function>("runMapWorker")
                     .or_else(|_| ctx.eval("runMapWorker"))
@@ -338,6 +1452,8 @@ async fn handle_job(vm: &Vm, job: JobRequest) {
             let _ = async_with!(vm.ctx => |ctx| {
                 ctx.globals().set("nextMapItem", rquickjs::Value::new_null(ctx.clone()))?;
                 ctx.globals().set("sendMapResults", rquickjs::Value::new_null(ctx.clone()))?;
+                ctx.globals().set("emit", rquickjs::Value::new_null(ctx.clone()))?;
+                ctx.globals().set("flushEmitted", rquickjs::Value::new_null(ctx.clone()))?;
                 Ok::<_, rquickjs::Error>(())
             })
             .await;
@@ -400,26 +1516,23 @@ async fn handle_job(vm: &Vm, job: JobRequest) {
 }
 
 #[instrument(level = "trace")]
-pub fn run_test_file(code: String) -> Result<()> {
-    let handle = thread::spawn(move || {
+pub fn run_test_file(script: CompiledScript, params: Value, options: VmOptions) -> Result<()> {
+    let handle = vm_thread(&options).spawn(move || {
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .context("Failed to create Tokio runtime")?;
 
         runtime.block_on(async move {
-            let vm = Vm::new()
+            let vm = new_vm(&options).await?;
+
+            load_script(&vm, &script, params)
                 .await
-                .map_err(|e| anyhow::anyhow!(e.to_string()))
-                .context("Failed to create VM")?;
+                .map_err(|e| anyhow::anyhow!("JS eval error: {}", e))?;
 
-            vm.ctx
-                .with(|ctx| {
-                    ctx.eval::<(), _>(code)
-                        .catch(&ctx)
-                        .map_err(|e| anyhow::anyhow!("JS eval error: {}", e))
-                })
-                .await?;
+            run_hook(&vm, "runSetup")
+                .await
+                .map_err(|e| anyhow::anyhow!("Error in setup: {}", e))?;
 
             let result = async_with!(vm.ctx => |ctx| {
                 let test_fn = ctx.globals()
@@ -442,11 +1555,15 @@ pub fn run_test_file(code: String) -> Result<()> {
             })
             .await;
 
+            let teardown = run_hook(&vm, "runTeardown")
+                .await
+                .map_err(|e| anyhow::anyhow!("Error in teardown: {}", e));
             let _ = vm.idle().await;
 
-            result
+            result.and(teardown)
         })
-    });
+    })
+    .context("Failed to start JS VM thread")?;
 
     // Wait for the thread and propagate errors
     handle
@@ -1,23 +1,39 @@
+mod failure;
+mod input;
 mod js;
+mod sort;
 
 use bincode::{deserialize, serialize};
-use futures::stream::StreamExt;
+use failure::{Failures, OnError, Phase};
+use futures::stream::{BoxStream, FuturesUnordered, StreamExt, TryStreamExt};
 use js::{JobRequest, JobResult};
-use std::{collections::HashMap, sync::atomic::AtomicUsize};
+use sort::{SortBy, SortOrder};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncWriteExt, BufWriter},
     sync::oneshot,
     task::JoinHandle,
 };
-use tokio_stream::wrappers::LinesStream;
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::{debug, error, info};
 
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::fmt::{Debug, Display};
 use tracing::instrument;
 
 const DEFAULT_SCRIPT: &str = include_str!("../default_script.js");
+const DEFAULT_MAP_EXPR: &str = "[[line, 1]]";
+const DEFAULT_REDUCE_EXPR: &str = "values.reduce((sum, value) => sum + value, 0)";
 
 fn merge_values(_key: &[u8], old_value: Option<&[u8]>, new_bytes: &[u8]) -> Option<Vec<u8>> {
     let mut values: Vec<js::Value> = old_value
@@ -29,7 +45,6 @@ fn merge_values(_key: &[u8], old_value: Option<&[u8]>, new_bytes: &[u8]) -> Opti
 }
 
 const DEFAULT_CHUNK_SIZE: usize = 64;
-const MAX_GROUPING_HEAP_BYTES: usize = 1024 * 1024 * 1024; // 1 GiB
 
 const HASHMAP_SLOT_SIZE: usize = {
     let size = std::mem::size_of::<(String, Vec<js::Value>)>();
@@ -41,6 +56,9 @@ const HASHMAP_SLOT_SIZE: usize = {
     (size / align) * align
 };
 
+/// Key along with every value mapped to it
+type Group = (String, Vec<js::Value>);
+
 enum GroupStorage {
     Memory(HashMap<String, Vec<js::Value>>),
     Sled(sled::Db),
@@ -50,10 +68,46 @@ enum GroupStorage {
 #[command(name = "pulsar")]
 #[command(about = "A simple map-reduce engine for parallel processing")]
 #[command(author, version)]
+// `-f` takes several values, which would otherwise swallow a subcommand that follows it
+#[command(subcommand_precedence_over_arg = true)]
 pub struct Cli {
-    /// Input file to read input data from.
-    #[arg(short = 'f', default_value = "-")]
-    input_file: String,
+    /// Input files to read input data from. Accepts several paths, globs and directories
+    /// (read recursively). Use `-` for stdin.
+    #[arg(short = 'f', default_value = "-", num_args = 1..)]
+    input_files: Vec<String>,
+
+    /// Input format. With `ndjson`, each line is parsed as JSON before being passed to `map`.
+    /// With `csv` or `tsv`, each record is passed as an object keyed by column name.
+    #[arg(long = "input-format", default_value_t = InputFormat::Lines)]
+    input_format: InputFormat,
+
+    /// Field delimiter for delimited input. Defaults to `,` for csv and a tab for tsv.
+    #[arg(long = "delimiter")]
+    delimiter: Option<char>,
+
+    /// Escape character inside quoted fields of delimited input. Doubled quotes are always accepted.
+    #[arg(long = "escape")]
+    escape: Option<char>,
+
+    /// Treat the first record of delimited input as data and pass records to `map` as arrays.
+    #[arg(long = "no-header", action = clap::ArgAction::SetTrue)]
+    no_header: bool,
+
+    /// What to do with input records that are not valid UTF-8. With `bytes`, every record
+    /// is passed to `map` as a `Uint8Array`.
+    #[arg(long = "invalid-utf8", default_value_t = input::InvalidUtf8::Skip)]
+    invalid_utf8: input::InvalidUtf8,
+
+    /// Record separator used instead of newlines for line and NDJSON input, such as `\0`
+    /// or any byte string (`\n`, `\r`, `\t`, `\\` and `\xHH` escapes are supported).
+    /// An empty separator enables paragraph mode, where records are separated by blank lines.
+    #[arg(long = "record-separator")]
+    record_separator: Option<String>,
+
+    /// Regular expression matching the first line of a record. Lines that don't match are
+    /// appended to the current record, e.g. to keep multi-line stack traces together.
+    #[arg(long = "record-start")]
+    record_start: Option<String>,
 
     /// Output format for the results.
     #[arg(long = "output", default_value_t = OutputFormat::Plain)]
@@ -63,10 +117,63 @@ pub struct Cli {
     #[arg(short = 's', long = "script")]
     script_file: Option<String>,
 
+    /// Inline JavaScript script, used instead of a script file.
+    #[arg(short = 'e', long = "eval", value_name = "JS", conflicts_with = "script_file")]
+    eval: Option<String>,
+
+    /// Expression defining `map`, evaluated with `line` and `meta` in scope, e.g.
+    /// `--map 'line.split(",").slice(0, 1).map(k => [k, 1])'`.
+    /// Defaults to `[[line, 1]]` when only `--reduce` is given.
+    #[arg(long = "map", value_name = "EXPR")]
+    map_expr: Option<String>,
+
+    /// Expression defining `reduce`, evaluated with `key` and `values` in scope.
+    /// Defaults to the sum of the values when only `--map` is given.
+    #[arg(long = "reduce", value_name = "EXPR")]
+    reduce_expr: Option<String>,
+
+    /// Job parameter exposed to the script as `params.<key>`, as `key=value`. Can be repeated.
+    #[arg(long = "param", value_name = "KEY=VALUE")]
+    params: Vec<String>,
+
+    /// JSON file with an object of job parameters. Values given with `--param` take precedence.
+    #[arg(long = "params-file")]
+    params_file: Option<String>,
+
     /// Whether to sort the output before printing. Assumes the script has a `sort` function.
-    #[arg(long = "sort", action = clap::ArgAction::SetTrue)]
+    #[arg(long = "sort", action = clap::ArgAction::SetTrue, conflicts_with = "sort_by")]
     sort: bool,
 
+    /// Sort the output by key or value natively, without a `sort` function in the script.
+    #[arg(long = "sort-by", visible_alias = "by", value_name = "FIELD")]
+    sort_by: Option<SortBy>,
+
+    /// Only output the first N results in `--sort-by` order, keeping no more than N in memory.
+    #[arg(long = "top", value_name = "N", requires = "sort_by")]
+    top: Option<usize>,
+
+    /// Sort in ascending order (the default).
+    #[arg(long = "asc", action = clap::ArgAction::SetTrue, requires = "sort_by", conflicts_with = "desc")]
+    asc: bool,
+
+    /// Sort in descending order.
+    #[arg(long = "desc", action = clap::ArgAction::SetTrue, requires = "sort_by")]
+    desc: bool,
+
+    /// Compare as numbers instead of lexicographically; values that aren't numbers sort last.
+    #[arg(long = "numeric", action = clap::ArgAction::SetTrue, requires = "sort_by")]
+    numeric: bool,
+
+    /// Memory for sorting with `--sort-by`, in bytes or with a K, M or G suffix. Larger
+    /// outputs are sorted in runs spilled to temporary files and merged.
+    #[arg(long = "sort-buffer-size", value_name = "SIZE", value_parser = parse_size, default_value = "512M")]
+    sort_buffer_size: usize,
+
+    /// Memory for grouping map output by key, in bytes or with a K, M or G suffix. Groups
+    /// that don't fit are spilled to an on-disk database and read back to be reduced.
+    #[arg(long = "group-buffer-size", value_name = "SIZE", value_parser = parse_size, default_value = "1G")]
+    group_buffer_size: usize,
+
     /// Number of parallel JS VM workers. Defaults to the number of logical CPUs.
     #[arg(short = 'j', long = "workers")]
     workers: Option<usize>,
@@ -75,13 +182,136 @@ pub struct Cli {
     #[arg(long = "test", action = clap::ArgAction::SetTrue)]
     test: bool,
 
+    /// Divide uncompressed files larger than this many bytes into splits of this size,
+    /// each read in parallel. Only applies to line and NDJSON input. Records read from
+    /// splits get `{ file, offset }` instead of `{ file, line }`.
+    #[arg(long = "split-size", value_name = "BYTES")]
+    split_size: Option<u64>,
+
     /// Number of lines per chunk sent to each worker.
     #[arg(short = 'c', long = "chunk-size", default_value_t = DEFAULT_CHUNK_SIZE)]
     chunk_size: usize,
 
+    /// Directory to cache the compiled script bytecode in, reused while the script is unchanged.
+    #[arg(long = "bytecode-cache", value_name = "DIR")]
+    bytecode_cache: Option<PathBuf>,
+
+    /// Number of times to retry `map` or `reduce` for a record that throws before applying `--on-error`.
+    #[arg(long = "retries", default_value_t = 0)]
+    retries: u32,
+
+    /// Seconds `map` or `reduce` may take for a single record, including awaited promises.
+    /// Records that take longer are interrupted and handled according to `--on-error`.
+    #[arg(long = "record-timeout", value_name = "SECONDS", value_parser = parse_seconds)]
+    record_timeout: Option<Duration>,
+
+    /// Seconds the whole job may take before it's stopped with an error.
+    #[arg(long = "job-timeout", value_name = "SECONDS", value_parser = parse_seconds)]
+    job_timeout: Option<Duration>,
+
+    /// What to do when `map` or `reduce` throws. The job exits with status 2 when records
+    /// were skipped, and a summary of the failures is printed.
+    #[arg(long = "on-error", default_value_t = OnError::Fail)]
+    on_error: OnError,
+
+    /// File to write failures to with `--on-error dead-letter`, one JSON object per line.
+    #[arg(long = "dead-letter", value_name = "FILE", default_value = "dead-letter.ndjson")]
+    dead_letter: PathBuf,
+
+    /// Memory limit of each JS VM, in bytes or with a K, M or G suffix. Allocations past
+    /// the limit fail the record being processed instead of exhausting the machine's memory.
+    #[arg(long = "vm-memory-limit", value_name = "SIZE", value_parser = parse_size)]
+    vm_memory_limit: Option<usize>,
+
+    /// Maximum stack size of each JS VM, in bytes or with a K, M or G suffix.
+    #[arg(long = "vm-stack-size", value_name = "SIZE", value_parser = parse_size)]
+    vm_stack_size: Option<usize>,
+
+    /// Run the JS code in a sandbox with only the pure builtin modules; no `fs`, `net`,
+    /// `child_process`, `fetch`, file imports or other ways to reach outside the VM.
+    /// Bytecode can't be loaded, as it isn't validated.
+    #[arg(long = "sandbox", action = clap::ArgAction::SetTrue, conflicts_with = "bytecode_cache")]
+    sandbox: bool,
+
+    /// Host modules to make available in the sandbox, comma separated.
+    #[arg(long = "sandbox-allow", value_name = "MODULE", value_delimiter = ',', requires = "sandbox")]
+    sandbox_allow: Vec<js::HostModule>,
+
+    /// Make runs reproducible: seed `Math.random` and `crypto` randomness in each worker,
+    /// freeze the clock and write the output in key order, which holds it all in memory.
+    #[arg(long = "deterministic", action = clap::ArgAction::SetTrue)]
+    deterministic: bool,
+
+    /// Seed for `Math.random` and `crypto` randomness with `--deterministic`.
+    #[arg(long = "seed", default_value_t = 0, requires = "deterministic")]
+    seed: u64,
+
+    /// Time returned by `Date.now()` with `--deterministic`, in milliseconds since the epoch.
+    #[arg(long = "now", value_name = "MILLIS", default_value_t = 0, requires = "deterministic", allow_negative_numbers = true)]
+    now: i64,
+
     /// Enable CPU profiling; writes pprof.pb to the working directory on exit.
     #[arg(long = "pprof", action = clap::ArgAction::SetTrue)]
     pub pprof: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Parse a number of seconds, which may be fractional
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string()))
+}
+
+/// Parse a size in bytes, optionally with a binary K, M or G suffix
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&value[..idx], 1 << 10),
+        Some((idx, 'm' | 'M')) => (&value[..idx], 1 << 20),
+        Some((idx, 'g' | 'G')) => (&value[..idx], 1 << 30),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<usize>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("{} is too large", value))
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compile a script to a `.pbc` bytecode file, which can be passed to `-s` instead.
+    Compile {
+        /// Script file to compile.
+        script: String,
+
+        /// Output file. Defaults to the script path with a `.pbc` extension.
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, ValueEnum, Default)]
+enum InputFormat {
+    #[default]
+    Lines,
+    Ndjson,
+    Csv,
+    Tsv,
+}
+
+impl Display for InputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputFormat::Lines => write!(f, "lines"),
+            InputFormat::Ndjson => write!(f, "ndjson"),
+            InputFormat::Csv => write!(f, "csv"),
+            InputFormat::Tsv => write!(f, "tsv"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, ValueEnum, Default)]
@@ -100,52 +330,178 @@ impl Display for OutputFormat {
     }
 }
 
-pub struct Pulsar<R: AsyncBufReadExt + Unpin> {
-    reader: R,
-    script: String,
+pub struct Pulsar {
+    inputs: Vec<input::Source>,
+    script: js::Script,
+    bytecode_cache: Option<PathBuf>,
+    compile_output: Option<PathBuf>,
+    params: js::Value,
     sort: bool,
+    sort_by: Option<SortOrder>,
+    sort_buffer_size: usize,
+    group_buffer_size: usize,
+    top: Option<usize>,
+    input_format: InputFormat,
+    csv_options: input::CsvOptions,
+    framing: input::Framing,
+    invalid_utf8: input::InvalidUtf8,
     output_format: OutputFormat,
+    on_error: OnError,
+    retries: u32,
+    dead_letter: PathBuf,
+    record_timeout: Option<Duration>,
+    job_timeout: Option<Duration>,
+    vm_memory_limit: Option<usize>,
+    vm_stack_size: Option<usize>,
+    sandbox: Option<Vec<js::HostModule>>,
+    deterministic: Option<js::Deterministic>,
     test: bool,
     workers: usize,
     chunk_size: usize,
+    split_size: Option<u64>,
     pprof_guard: Option<pprof2::ProfilerGuard<'static>>,
 }
 
-impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
+impl Pulsar {
     /// Create a new Pulsar instance from CLI arguments
     #[instrument(level = "trace")]
-    pub async fn from_cli(cli: Cli) -> Result<Self> {
-        let reader: BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>> =
-            if cli.input_file == "-" {
-                // Read from stdin
-                let stdin = tokio::io::stdin();
-                BufReader::new(Box::new(stdin))
-            } else {
-                // Read from file
-                let file = tokio::fs::File::open(&cli.input_file).await.map_err(|e| {
-                    anyhow::anyhow!("Failed to open file {}: {}", cli.input_file, e)
-                })?;
-                BufReader::new(Box::new(file))
-            };
+    pub async fn from_cli(mut cli: Cli) -> Result<Self> {
+        let inputs = input::resolve_sources(&cli.input_files)?;
 
-        let script = if let Some(script_file) = cli.script_file {
-            // Read custom script from file
-            tokio::fs::read_to_string(&script_file)
-                .await
-                .map_err(|e| anyhow::anyhow!("Failed to read script file {}: {}", script_file, e))?
-        } else {
-            // Use default word count script
-            DEFAULT_SCRIPT.into()
+        let compile_output = match cli.command.take() {
+            Some(Command::Compile { script, output }) => {
+                let inline = cli.map_expr.is_some() || cli.reduce_expr.is_some() || cli.eval.is_some();
+                if inline || cli.script_file.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "-s, -e, --map and --reduce can't be combined with compile, which only compiles its script argument"
+                    ));
+                }
+                let output = output.unwrap_or_else(|| Path::new(&script).with_extension("pbc"));
+                cli.script_file = Some(script);
+                Some(output)
+            }
+            None => None,
+        };
+        let script = match cli.script_file.as_deref() {
+            Some(script_file) if js::is_bytecode(script_file) && cli.sandbox => {
+                return Err(anyhow::anyhow!(
+                    "Compiled scripts can't be run with --sandbox, pass the script source instead"
+                ));
+            }
+            Some(script_file) if js::is_bytecode(script_file) => {
+                if cli.map_expr.is_some() || cli.reduce_expr.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "--map and --reduce can't be combined with a compiled script"
+                    ));
+                }
+                js::Script::Compiled(js::CompiledScript::read(Path::new(script_file))?)
+            }
+            _ => Self::read_script(&mut cli).await?,
+        };
+        let mut params = match &cli.params_file {
+            Some(params_file) => {
+                let contents = tokio::fs::read_to_string(params_file).await.map_err(|e| {
+                    anyhow::anyhow!("Failed to read params file {}: {}", params_file, e)
+                })?;
+                match serde_json::from_str(&contents) {
+                    Ok(serde_json::Value::Object(params)) => params,
+                    Ok(_) => {
+                        return Err(anyhow::anyhow!(
+                            "Params file {} must contain a JSON object",
+                            params_file
+                        ));
+                    }
+                    Err(e) => {
+                        return Err(anyhow::anyhow!(
+                            "Failed to parse params file {}: {}",
+                            params_file,
+                            e
+                        ));
+                    }
+                }
+            }
+            None => serde_json::Map::new(),
         };
+        for param in &cli.params {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("--param must be KEY=VALUE, got {:?}", param))?;
+            params.insert(key.to_string(), serde_json::Value::String(value.to_string()));
+        }
         let workers = cli.workers.unwrap_or_else(num_cpus::get_physical).max(1);
+
+        let ascii_byte = |flag: &str, c: char| -> Result<u8> {
+            if c.is_ascii() {
+                Ok(c as u8)
+            } else {
+                Err(anyhow::anyhow!("{} must be a single ASCII character, got {:?}", flag, c))
+            }
+        };
+        let default_delimiter = match cli.input_format {
+            InputFormat::Tsv => '\t',
+            _ => ',',
+        };
+        let csv_options = input::CsvOptions {
+            delimiter: ascii_byte("--delimiter", cli.delimiter.unwrap_or(default_delimiter))?,
+            escape: cli.escape.map(|c| ascii_byte("--escape", c)).transpose()?,
+            header: !cli.no_header,
+        };
+        let framing = input::Framing::from_flags(
+            cli.record_separator.as_deref(),
+            cli.record_start.as_deref(),
+        )?;
+        if matches!(cli.input_format, InputFormat::Csv | InputFormat::Tsv)
+            && !matches!(framing, input::Framing::Lines)
+        {
+            return Err(anyhow::anyhow!(
+                "--record-separator and --record-start are not supported with {} input",
+                cli.input_format
+            ));
+        }
+        if cli.invalid_utf8 == input::InvalidUtf8::Bytes
+            && !matches!(cli.input_format, InputFormat::Lines)
+        {
+            return Err(anyhow::anyhow!(
+                "--invalid-utf8=bytes is not supported with {} input",
+                cli.input_format
+            ));
+        }
         Ok(Pulsar {
-            reader,
-            script: script.clone(),
+            inputs,
+            script,
+            bytecode_cache: cli.bytecode_cache,
+            compile_output,
+            params: serde_json::Value::Object(params).into(),
+            input_format: cli.input_format,
+            csv_options,
+            framing,
+            invalid_utf8: cli.invalid_utf8,
             output_format: cli.output_format,
+            on_error: cli.on_error,
+            retries: cli.retries,
+            record_timeout: cli.record_timeout,
+            job_timeout: cli.job_timeout,
+            vm_memory_limit: cli.vm_memory_limit,
+            vm_stack_size: cli.vm_stack_size,
+            sandbox: cli.sandbox.then_some(cli.sandbox_allow),
+            deterministic: cli.deterministic.then_some(js::Deterministic {
+                seed: cli.seed,
+                now: cli.now,
+            }),
+            dead_letter: cli.dead_letter,
             sort: cli.sort,
+            sort_by: cli.sort_by.map(|by| SortOrder {
+                by,
+                descending: cli.desc,
+                numeric: cli.numeric,
+            }),
+            sort_buffer_size: cli.sort_buffer_size,
+            group_buffer_size: cli.group_buffer_size,
+            top: cli.top,
             test: cli.test,
             workers,
             chunk_size: cli.chunk_size.max(1),
+            split_size: cli.split_size,
             pprof_guard: if cli.pprof {
                 Some(pprof2::ProfilerGuard::new(999).unwrap())
             } else {
@@ -154,37 +510,132 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
         })
     }
 
+    /// Read the script from a file, `-e` or `--map`/`--reduce`, falling back to the default script
+    async fn read_script(cli: &mut Cli) -> Result<js::Script> {
+        let mut module_path = None;
+        let mut bytecode = js::Precompiled::default();
+        let (script_name, mut script) = if let Some(script_file) = cli.script_file.take() {
+            // Read custom script from file
+            let mut source = tokio::fs::read_to_string(&script_file)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read script file {}: {}", script_file, e))?;
+            if js::is_typescript(&script_file) {
+                source = js::strip_types(&script_file, source)?;
+            }
+            // Imports are resolved relative to the script, so a module is given its full path
+            let full_path = std::fs::canonicalize(&script_file)?.to_string_lossy().into_owned();
+            let (module, compiled) = js::detect_module(&script_file, &full_path, &source);
+            if module {
+                module_path = Some(full_path);
+            }
+            bytecode = compiled;
+            (script_file, source)
+        } else if let Some(eval) = cli.eval.take() {
+            ("eval_script".into(), eval)
+        } else if cli.map_expr.is_some() || cli.reduce_expr.is_some() {
+            // Build the whole script from the inline expressions, filling in the missing one
+            cli.map_expr.get_or_insert_with(|| DEFAULT_MAP_EXPR.into());
+            cli.reduce_expr.get_or_insert_with(|| DEFAULT_REDUCE_EXPR.into());
+            ("eval_script".into(), String::new())
+        } else {
+            // Use default word count script
+            ("default_script.js".into(), DEFAULT_SCRIPT.into())
+        };
+        let export = if module_path.is_some() { "export " } else { "" };
+        if cli.map_expr.is_some() || cli.reduce_expr.is_some() {
+            // The bytecode doesn't have the functions added below
+            bytecode = js::Precompiled::default();
+        }
+        if let Some(map) = &cli.map_expr {
+            script.push_str(&format!("\n{}const map = async (line, meta) => ({}\n);\n", export, map));
+        }
+        if let Some(reduce) = &cli.reduce_expr {
+            script.push_str(&format!(
+                "\n{}const reduce = async (key, values) => ({}\n);\n",
+                export, reduce
+            ));
+        }
+        Ok(match module_path {
+            Some(path) => js::Script::Module { path, source: script, bytecode },
+            None => js::Script::Classic { name: script_name, source: script, bytecode },
+        })
+    }
+
     /// Run the application with streaming and optimized processing
     #[instrument(level = "trace")]
-    pub async fn run(self) -> Result<()> {
+    pub async fn run(self) -> Result<ExitCode> {
         if self.test {
-            return self.run_tests().await;
+            self.run_tests().await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        if let Some(output) = &self.compile_output {
+            let script = self
+                .script
+                .compile(self.bytecode_cache.as_deref())
+                .map_err(|e| anyhow::anyhow!("Failed to compile script: {}", e))?;
+            // The module is named by its path here, so imported files would have to be at
+            // the same paths wherever the bytecode is run
+            let imports = script.file_imports()?;
+            if !imports.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Can't compile a module that imports other files ({}), bundle it into a single file first",
+                    imports.join(", ")
+                ));
+            }
+            script.write(output)?;
+            println!("Wrote {}", output.display());
+            return Ok(ExitCode::SUCCESS);
         }
 
-        self.run_engine().await?;
-        Ok(())
+        self.run_engine().await
     }
 
     #[instrument(level = "trace")]
     pub async fn run_tests(&self) -> Result<()> {
-        js::run_test_file(self.script.clone())?;
+        let script = self
+            .script
+            .compile(self.bytecode_cache.as_deref())
+            .map_err(|e| anyhow::anyhow!("JS eval error: {}", e))?;
+        js::run_test_file(script, self.params.clone(), self.vm_options())?;
         println!("OK");
         Ok(())
     }
 
     #[instrument(level = "trace")]
-    pub async fn run_engine(self) -> Result<()> {
+    pub async fn run_engine(self) -> Result<ExitCode> {
         let n_cpus = self.workers;
         info!("Starting pulsar engine with {} CPU workers", n_cpus);
+        // Compile the script once, every worker loads the same bytecode
+        let script = self
+            .script
+            .compile(self.bytecode_cache.as_deref())
+            .map_err(|e| anyhow::anyhow!("Error loading JS code: {}", e))?;
+        let failures = Arc::new(Failures::new(self.on_error, self.retries, &self.dead_letter)?);
+        let vm_options = js::VmOptions {
+            record_timeout: self.record_timeout,
+            job_deadline: self.job_timeout.map(|timeout| Instant::now() + timeout),
+            ..self.vm_options()
+        };
         let (worker_tx, worker_rx) = flume::bounded(self.chunk_size);
         let mut init_rxs = Vec::with_capacity(n_cpus);
+        let mut vm_workers = Vec::with_capacity(n_cpus);
 
         for idx in 0..n_cpus {
             let (init_tx, init_rx) = oneshot::channel();
             init_rxs.push(init_rx);
-            if let Err(e) = js::start_vm_worker(self.script.clone(), worker_rx.clone(), init_tx) {
-                error!("Failed to start JS VM worker {}: {}", idx, e);
-                return Err(e.into());
+            match js::start_vm_worker(
+                script.clone(),
+                self.params.clone(),
+                failures.clone(),
+                vm_options.for_worker(idx),
+                worker_rx.clone(),
+                init_tx,
+            ) {
+                Ok(handle) => vm_workers.push(handle),
+                Err(e) => {
+                    error!("Failed to start JS VM worker {}: {}", idx, e);
+                    return Err(e.into());
+                }
             }
         }
         // Drop the original so the channel closes when all workers exit
@@ -195,14 +646,80 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
         for init_rx in init_rxs {
             match init_rx.await {
                 Ok(Ok(())) => {}
-                Ok(Err(_)) | Err(_) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(anyhow::anyhow!("JS VM worker exited during initialisation")),
             }
         }
         info!("Successfully started {} JS VM workers", n_cpus);
 
+        let phases = self.run_phases(n_cpus, worker_tx.clone(), failures.clone());
+        let result = match vm_options.job_deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), phases).await,
+            None => Ok(phases.await),
+        };
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                // Workers may be stuck on the records that failed, so they aren't waited for
+                failures.finish()?;
+                return Err(e);
+            }
+            Err(_) => {
+                // Workers stop at the deadline as well, without running teardown
+                drop(worker_tx);
+                let _ = tokio::task::spawn_blocking(move || {
+                    vm_workers.into_iter().for_each(|handle| {
+                        let _ = handle.join();
+                    })
+                })
+                .await;
+                failures.finish()?;
+                let timeout = self.job_timeout.unwrap_or_default();
+                return Err(anyhow::anyhow!("Job timed out after {}s", timeout.as_secs_f64()));
+            }
+        }
+
+        // Close the job channel so every worker runs its teardown hook and exits
+        drop(worker_tx);
+        let teardown = tokio::task::spawn_blocking(move || {
+            vm_workers.into_iter().try_for_each(|handle| {
+                handle
+                    .join()
+                    .map_err(|e| anyhow::anyhow!("JS VM worker panicked: {:?}", e))?
+            })
+        })
+        .await?;
+        info!("Pulsar processing completed successfully");
+
+        if let Some(guard) = self.pprof_guard {
+            if let Ok(report) = guard.report().build() {
+                use pprof2::protos::Message;
+                use std::io::Write;
+                let profile = report.pprof()?;
+                let mut content = Vec::new();
+                profile.encode(&mut content)?;
+                let mut file = std::fs::File::create("pprof.pb")?;
+                file.write_all(&content)?;
+            }
+        }
+
+        failures.finish()?;
+        teardown?;
+        // Exit with 2 when the job completed but skipped failed records
+        Ok(if failures.is_empty() { ExitCode::SUCCESS } else { ExitCode::from(2) })
+    }
+
+    /// Run the map, group and reduce phases on the started workers and write the output
+    async fn run_phases(
+        &self,
+        n_cpus: usize,
+        worker_tx: flume::Sender<JobRequest>,
+        failures: Arc<Failures>,
+    ) -> Result<()> {
         // aggregate map results — workers send one Vec<KeyValue> per input line
         let (map_result_tx, map_result_rx) =
             tokio::sync::mpsc::channel::<Vec<js::KeyValue>>(n_cpus * self.chunk_size);
+        let group_buffer_size = self.group_buffer_size;
         let map_consumer: JoinHandle<Result<GroupStorage>> = tokio::spawn(async move {
             let mut map_result_rx = map_result_rx;
             let mut overflow_db: Option<sled::Db> = None;
@@ -216,7 +733,7 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
                 }
 
                 // check if we need to spill to disk
-                if HASHMAP_SLOT_SIZE * hashmap.capacity() >= MAX_GROUPING_HEAP_BYTES {
+                if HASHMAP_SLOT_SIZE * hashmap.capacity() >= group_buffer_size {
                     info!(
                         "Flushing {} entries to DB, total processed: {}",
                         hashmap.len(),
@@ -264,17 +781,22 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
 
         // map phase: dispatch RunMapPhase to each worker, then stream lines into the shared channel
         info!("Starting map phase");
-        let (map_item_tx, map_item_rx) = flume::bounded::<String>(n_cpus * self.chunk_size);
+        let (map_item_tx, map_item_rx) = flume::bounded::<js::MapItem>(n_cpus * self.chunk_size);
 
         let mut map_done_rxs = Vec::with_capacity(n_cpus);
         for _ in 0..n_cpus {
             let (done_tx, done_rx) = oneshot::channel::<anyhow::Result<()>>();
-            map_done_rxs.push(done_rx);
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            map_done_rxs.push({
+                let in_flight = in_flight.clone();
+                async move { (done_rx.await, in_flight.load(Ordering::Relaxed)) }
+            });
             worker_tx
                 .send_async(JobRequest::RunMapPhase {
                     item_rx: map_item_rx.clone(),
                     result_tx: map_result_tx.clone(),
                     concurrency: self.chunk_size,
+                    in_flight,
                     done_tx,
                 })
                 .await?;
@@ -282,28 +804,40 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
         drop(map_item_rx);
         drop(map_result_tx); // workers hold the remaining Sender clones
 
-        let mut lines = LinesStream::new(self.reader.lines());
-        while let Some(line_res) = lines.next().await {
-            match line_res {
-                Ok(line) => {
-                    if map_item_tx.send_async(line).await.is_err() {
-                        break;
+        // Map workers report when they finish; with `--on-error fail` the first failure
+        // stops reading the input. A worker that dies loses the records it was mapping.
+        let map_workers = tokio::spawn({
+            let failures = failures.clone();
+            async move {
+                let mut map_done_rxs: FuturesUnordered<_> = map_done_rxs.into_iter().collect();
+                while let Some((done, in_flight)) = map_done_rxs.next().await {
+                    match done {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => failures.record(
+                            Phase::Map,
+                            in_flight,
+                            &e.to_string(),
+                            None,
+                            serde_json::json!({}),
+                        )?,
+                        Err(_) => failures.record(
+                            Phase::Map,
+                            in_flight,
+                            "JS VM worker exited during map phase",
+                            None,
+                            serde_json::json!({}),
+                        )?,
                     }
                 }
-                Err(e) => eprintln!("Error reading line: {}", e),
+                Ok::<_, anyhow::Error>(())
             }
-        }
-        drop(map_item_tx); // closing the channel signals workers: no more items
-
-        // Wait for all map workers to finish and drop their result_tx clones;
-        // once they do, map_result_rx closes and map_consumer terminates.
-        for done_rx in map_done_rxs {
-            match done_rx.await {
-                Ok(Ok(())) => {}
-                Ok(Err(e)) => error!("Map worker error: {}", e),
-                Err(e) => error!("Map worker channel error: {}", e),
-            }
-        }
+        });
+        // Reading drops map_item_tx, which signals workers: no more items. Once the workers
+        // finish they drop their result_tx clones, so map_result_rx closes and map_consumer terminates.
+        tokio::try_join!(
+            self.read_inputs(map_item_tx, failures.clone()),
+            async { map_workers.await? }
+        )?;
 
         // group phase
         info!("Map phase completed, starting group phase");
@@ -322,6 +856,11 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
         let reduce_consumer = tokio::spawn({
             let output_format = self.output_format.clone();
             let sort = self.sort;
+            let sort_by = self.sort_by;
+            let sort_buffer_size = self.sort_buffer_size;
+            let chunk_size = self.chunk_size;
+            let top = self.top;
+            let deterministic = self.deterministic.is_some();
             let worker_tx = worker_tx.clone();
             async move {
                 info!("Starting output writer task");
@@ -329,9 +868,56 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
                 let mut writer = BufWriter::new(stdout);
                 let mut result_count = 0;
 
-                if sort {
+                if let (Some(order), Some(n)) = (sort_by, top) {
+                    info!("Keeping the top {} results by {}", n, order.by);
+                    let mut top = sort::TopN::new(order, n);
+                    while let Some(kv) = reduce_rx.recv().await {
+                        top.push(kv);
+                        result_count += 1;
+                    }
+                    info!("Collected {} results, writing the top {}", result_count, n);
+                    for kv in top.finish() {
+                        Self::format_and_print_result(
+                            &kv.key,
+                            &kv.value,
+                            &output_format,
+                            &mut writer,
+                        )
+                        .await;
+                    }
+                } else if let Some(order) = sort_by {
+                    info!("Collecting results for sorting by {}", order.by);
+                    // Sorting and the IO on spilled runs block, so the sorter runs on a
+                    // blocking thread and hands the sorted pairs back over a channel
+                    let (sorted_tx, mut sorted_rx) = tokio::sync::mpsc::channel(chunk_size);
+                    let sorter = tokio::task::spawn_blocking(move || {
+                        let mut sorter = sort::ExternalSorter::new(order, sort_buffer_size);
+                        let mut result_count = 0;
+                        while let Some(kv) = reduce_rx.blocking_recv() {
+                            sorter.push(kv)?;
+                            result_count += 1;
+                        }
+                        info!("Collected {} results, writing sorted output", result_count);
+                        for kv in sorter.finish()? {
+                            if sorted_tx.blocking_send(kv?).is_err() {
+                                break;
+                            }
+                        }
+                        Ok::<_, anyhow::Error>(())
+                    });
+                    while let Some(kv) = sorted_rx.recv().await {
+                        Self::format_and_print_result(
+                            &kv.key,
+                            &kv.value,
+                            &output_format,
+                            &mut writer,
+                        )
+                        .await;
+                    }
+                    sorter.await??;
+                } else if sort || deterministic {
                     info!("Collecting results for sorting");
-                    let mut results = Vec::new();
+                    let mut results: Vec<js::KeyValue> = Vec::new();
                     while let Some(kv) = reduce_rx.recv().await {
                         results.push(kv);
                         result_count += 1;
@@ -341,25 +927,31 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
                         result_count
                     );
 
-                    let (resp_tx, resp_rx) = oneshot::channel();
-                    let _ = worker_tx.send_async(JobRequest::Sort(results, resp_tx)).await;
-                    match resp_rx.await {
-                        Ok(JobResult::SortSuccess(output)) => {
-                            info!(
-                                "Sort operation completed, writing {} sorted results",
-                                output.len()
-                            );
-                            for kv in output {
-                                Self::format_and_print_result(
-                                    &kv.key,
-                                    &kv.value,
-                                    &output_format,
-                                    &mut writer,
-                                )
-                                .await;
-                            }
+                    // Reduce batches finish in any order; key order makes the output, and the
+                    // input of a stable `sort`, the same on every run
+                    if deterministic {
+                        results.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+                    }
+                    let output = if sort {
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        let _ = worker_tx.send_async(JobRequest::Sort(results, resp_tx)).await;
+                        match resp_rx.await {
+                            Ok(JobResult::SortSuccess(output)) => output,
+                            Ok(JobResult::Error(e)) => return Err(anyhow::anyhow!("Sort error: {}", e)),
+                            _ => return Err(anyhow::anyhow!("Sort error: JS VM worker exited")),
                         }
-                        _ => error!("Sort error"),
+                    } else {
+                        results
+                    };
+                    info!("Sort operation completed, writing {} sorted results", output.len());
+                    for kv in output {
+                        Self::format_and_print_result(
+                            &kv.key,
+                            &kv.value,
+                            &output_format,
+                            &mut writer,
+                        )
+                        .await;
                     }
                 } else {
                     info!("Writing results without sorting");
@@ -377,46 +969,67 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
                 }
 
                 let _ = writer.flush().await;
+                Ok::<_, anyhow::Error>(())
             }
         });
 
         // reduce phase
         info!("Starting reduce phase");
         let task_idx = AtomicUsize::new(0);
-        let reduce_entries: Vec<(String, Vec<js::Value>)> = match groups {
-            GroupStorage::Memory(hashmap) => hashmap.into_iter().collect(),
-            GroupStorage::Sled(db) => db.iter()
-                .filter_map(|res| {
-                    match res {
-                        Ok((key, value)) => Some((key, value)),
-                        Err(e) => {
-                            eprintln!("Error iterating sled db: {}", e);
-                            None
+        // Groups are read lazily as reduce batches are dispatched, so at most `n_cpus`
+        // batches of a spilled dataset are in memory at once. Reading from sled blocks, so
+        // it runs on a blocking thread that hands the batches over a channel, followed by
+        // the error that stopped it, if any, which fails the job.
+        let reduce_batches: BoxStream<'static, Result<Vec<Group>>> = match groups {
+            GroupStorage::Memory(hashmap) => tokio_stream::iter(hashmap)
+                .chunks(self.chunk_size)
+                .map(Ok)
+                .boxed(),
+            GroupStorage::Sled(db) => {
+                let (batch_tx, batch_rx) = tokio::sync::mpsc::channel(n_cpus);
+                let chunk_size = self.chunk_size;
+                tokio::task::spawn_blocking(move || {
+                    let mut batch = Vec::with_capacity(chunk_size);
+                    for entry in db.iter() {
+                        let entry = entry
+                            .map_err(|e| anyhow::anyhow!("Failed to read spilled groups: {}", e))
+                            .and_then(|(key, value_bytes)| {
+                                let key = String::from_utf8_lossy(&key).into_owned();
+                                let values: Vec<js::Value> = deserialize(&value_bytes).map_err(|e| {
+                                    anyhow::anyhow!("Failed to read spilled values of key '{}': {}", key, e)
+                                })?;
+                                Ok((key, values))
+                            });
+                        match entry {
+                            Ok(entry) => batch.push(entry),
+                            Err(e) => {
+                                let _ = batch_tx.blocking_send(Err(e));
+                                return;
+                            }
+                        }
+                        if batch.len() == chunk_size {
+                            let full = std::mem::replace(&mut batch, Vec::with_capacity(chunk_size));
+                            if batch_tx.blocking_send(Ok(full)).is_err() {
+                                return;
+                            }
                         }
                     }
-                })
-                .map(|(k, value_bytes)| {
-                    let key: String = String::from_utf8_lossy(&k).to_string();
-                    let values: Vec<js::Value> = deserialize(&value_bytes).unwrap_or_else(|e| {
-                        error!(
-                            "Failed to deserialize DB value for key '{:?}': {}. Discarding corrupted data.",
-                            &k,
-                            e
-                        );
-                        Vec::new()
-                    });
-                    (key, values)
-                })
-                .collect(),
+                    if !batch.is_empty() {
+                        let _ = batch_tx.blocking_send(Ok(batch));
+                    }
+                });
+                ReceiverStream::new(batch_rx).boxed()
+            }
         };
-        tokio_stream::iter(reduce_entries)
-        .chunks(self.chunk_size)
-        .for_each_concurrent(n_cpus, |batch: Vec<(String, Vec<js::Value>)>| {
+        reduce_batches
+        .try_for_each_concurrent(n_cpus, |batch: Vec<(String, Vec<js::Value>)>| {
             let idx = task_idx.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             let worker_tx = worker_tx.clone();
             let reduce_tx = reduce_tx.clone();
+            let failures = failures.clone();
 
             async move {
+                let keys: Vec<String> = batch.iter().map(|(key, _)| key.clone()).collect();
                 let (resp_tx, resp_rx) = oneshot::channel();
                 let _ = worker_tx.send_async(JobRequest::Reduce(batch, resp_tx)).await;
 
@@ -432,34 +1045,95 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
                     }
                     Ok(JobResult::Error(e)) => {
                         error!("Error during reduce task {}: {}", idx, e);
+                        failures.record(Phase::Reduce, keys.len(), &e, None, serde_json::json!({ "keys": keys }))?;
                     }
                     Err(e) => {
                         error!("JS worker error in reduce task {}: {}", idx, e);
+                        failures.record(
+                            Phase::Reduce,
+                            keys.len(),
+                            "JS VM worker exited during reduce phase",
+                            None,
+                            serde_json::json!({ "keys": keys }),
+                        )?;
                     }
                     _ => unreachable!(),
                 };
+                Ok::<_, anyhow::Error>(())
             }
         })
-        .await;
+        .await?;
 
         // write results
         info!("Reduce phase completed, waiting for output");
         drop(reduce_tx);
-        let _ = reduce_consumer.await;
-        info!("Pulsar processing completed successfully");
+        reduce_consumer.await??;
+        Ok(())
+    }
 
-        if let Some(guard) = self.pprof_guard {
-            if let Ok(report) = guard.report().build() {
-                use pprof2::protos::Message;
-                use std::io::Write;
-                let profile = report.pprof()?;
-                let mut content = Vec::new();
-                profile.encode(&mut content)?;
-                let mut file = std::fs::File::create("pprof.pb")?;
-                file.write_all(&content)?;
-            }
+    /// Limits, sandboxing and determinism of the JS VMs that apply to every run mode
+    fn vm_options(&self) -> js::VmOptions {
+        js::VmOptions {
+            memory_limit: self.vm_memory_limit,
+            stack_size: self.vm_stack_size,
+            sandbox: self.sandbox.clone(),
+            deterministic: self.deterministic,
+            ..Default::default()
         }
+    }
 
+    /// Read all inputs into the map workers' channel. Records dropped while decoding
+    /// count as map failures.
+    async fn read_inputs(
+        &self,
+        map_item_tx: flume::Sender<js::MapItem>,
+        failures: Arc<Failures>,
+    ) -> Result<()> {
+        let ndjson = matches!(self.input_format, InputFormat::Ndjson);
+        let decoder = Arc::new(input::RecordDecoder::new(ndjson, self.invalid_utf8, failures));
+        for source in &self.inputs {
+            let name: Arc<str> = source.to_string().into();
+            let split = match (&self.input_format, source, self.split_size) {
+                (
+                    InputFormat::Lines | InputFormat::Ndjson,
+                    input::Source::File(path),
+                    Some(split_size),
+                ) if matches!(self.framing, input::Framing::Lines) => {
+                    input::split_len(source, split_size)
+                        .await
+                        .map(|len| (path, len, split_size))
+                }
+                _ => None,
+            };
+            let read_result = if let Some((path, len, split_size)) = split {
+                input::read_splits(
+                    path,
+                    &name,
+                    len,
+                    split_size,
+                    self.workers,
+                    &decoder,
+                    &map_item_tx,
+                )
+                .await
+            } else {
+                let reader = input::open(source).await?;
+                match self.input_format {
+                    InputFormat::Lines | InputFormat::Ndjson => {
+                        input::read_records(reader, &name, &self.framing, &decoder, &map_item_tx)
+                            .await
+                    }
+                    InputFormat::Csv | InputFormat::Tsv => {
+                        input::read_csv(reader, &name, &self.csv_options, &decoder, &map_item_tx)
+                            .await
+                    }
+                }
+            };
+            read_result.map_err(|e| anyhow::anyhow!("Error reading input {}: {}", name, e))?;
+            if map_item_tx.is_disconnected() {
+                break;
+            }
+        }
         Ok(())
     }
 
@@ -490,7 +1164,7 @@ impl Pulsar<BufReader<Box<dyn tokio::io::AsyncRead + Unpin + Send>>> {
     }
 }
 
-impl<R: AsyncBufReadExt + Unpin> Debug for Pulsar<R> {
+impl Debug for Pulsar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Pulsar").finish()
     }
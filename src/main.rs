@@ -1,5 +1,6 @@
 use anyhow::Result;
 use clap::Parser;
+use std::process::ExitCode;
 use tracing::debug;
 use tracing_subscriber::EnvFilter;
 
@@ -11,7 +12,7 @@ use tikv_jemallocator::Jemalloc;
 static GLOBAL: Jemalloc = Jemalloc;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let subscriber = tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .with_env_filter(EnvFilter::from_default_env())
@@ -0,0 +1,359 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use rayon::slice::ParallelSliceMut;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::js::{KeyValue, Value};
+
+/// Part of the reduced pairs the output is sorted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortBy {
+    Key,
+    Value,
+}
+
+impl Display for SortBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SortBy::Key => write!(f, "key"),
+            SortBy::Value => write!(f, "value"),
+        }
+    }
+}
+
+/// Ordering of the output with `--sort-by`. Pairs that compare equal are ordered by
+/// key, so the output is the same whichever order the pairs were reduced in.
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub by: SortBy,
+    pub descending: bool,
+    /// Compare as numbers rather than as text; what doesn't parse as a number sorts last
+    pub numeric: bool,
+}
+
+impl SortOrder {
+    fn compare(&self, a: &KeyValue, b: &KeyValue) -> Ordering {
+        let ordering = match (self.by, self.numeric) {
+            (SortBy::Key, false) => self.direct(a.key.cmp(&b.key)),
+            (SortBy::Key, true) => self.compare_numbers(a.key.parse().ok(), b.key.parse().ok()),
+            (SortBy::Value, false) => self.direct(text(&a.value).cmp(&text(&b.value))),
+            (SortBy::Value, true) => self.compare_numbers(number(&a.value), number(&b.value)),
+        };
+        ordering.then_with(|| a.key.cmp(&b.key))
+    }
+
+    fn direct(&self, ordering: Ordering) -> Ordering {
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Non-numbers sort last in either direction
+    fn compare_numbers(&self, a: Option<f64>, b: Option<f64>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => self.direct(a.total_cmp(&b)),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+fn text(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::String(s) => Cow::Borrowed(s),
+        value => Cow::Owned(value.to_string()),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Rough size of a pair in memory: the pair itself and what its strings and collections
+/// allocate
+fn memory_size(kv: &KeyValue) -> usize {
+    size_of::<KeyValue>() + kv.key.capacity() + heap_size(&kv.value)
+}
+
+fn heap_size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.capacity(),
+        Value::Bytes(b) => b.capacity(),
+        Value::Array(values) => {
+            values.capacity() * size_of::<Value>() + values.iter().map(heap_size).sum::<usize>()
+        }
+        // Hash tables keep a control byte per bucket next to the entries
+        Value::Object(map) => {
+            map.capacity() * (size_of::<(String, Value)>() + 1)
+                + map
+                    .iter()
+                    .map(|(key, value)| key.capacity() + heap_size(value))
+                    .sum::<usize>()
+        }
+        Value::Null | Value::Bool(_) | Value::Int(_) | Value::Float(_) => 0,
+    }
+}
+
+/// Sorts the reduced pairs in parallel, in runs of at most `buffer_size` bytes. Runs
+/// that don't fit are spilled to temporary files and merged back when reading.
+pub struct ExternalSorter {
+    order: SortOrder,
+    buffer_size: usize,
+    buffer: Vec<KeyValue>,
+    buffered_bytes: usize,
+    dir: PathBuf,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    pub fn new(order: SortOrder, buffer_size: usize) -> Self {
+        ExternalSorter {
+            order,
+            buffer_size,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            dir: std::env::temp_dir().join(format!("pulsar-sort-{}", std::process::id())),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, kv: KeyValue) -> Result<()> {
+        self.buffered_bytes += memory_size(&kv);
+        self.buffer.push(kv);
+        if self.buffered_bytes >= self.buffer_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort_buffer(&mut self) {
+        let order = self.order;
+        self.buffer.par_sort_unstable_by(|a, b| order.compare(a, b));
+    }
+
+    /// Sort the buffered pairs and write them to a new run file
+    fn spill(&mut self) -> Result<()> {
+        self.sort_buffer();
+        fs::create_dir_all(&self.dir).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to create sort directory {}: {}",
+                self.dir.display(),
+                e
+            )
+        })?;
+        let path = self.dir.join(format!("run-{}", self.runs.len()));
+        info!(
+            "Spilling {} sorted results to {}",
+            self.buffer.len(),
+            path.display()
+        );
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for kv in self.buffer.drain(..) {
+            bincode::serialize_into(&mut writer, &kv)?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    /// The pairs in sorted order, merged from the spilled runs if there are any
+    pub fn finish(mut self) -> Result<Sorted> {
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            let buffer = std::mem::take(&mut self.buffer);
+            return Ok(Sorted::Memory(buffer.into_iter()));
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        info!("Merging {} sorted runs", self.runs.len());
+        let mut readers = Vec::with_capacity(self.runs.len());
+        let mut heads = BinaryHeap::with_capacity(self.runs.len());
+        for (run, path) in self.runs.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(path)?);
+            if let Some(kv) = read_run(&mut reader)? {
+                heads.push(Reverse(Head {
+                    ranked: Ranked { order: self.order, kv },
+                    run,
+                }));
+            }
+            readers.push(reader);
+        }
+        Ok(Sorted::Merge(Merge {
+            order: self.order,
+            readers,
+            heads,
+            _sorter: self,
+        }))
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        if !self.runs.is_empty() {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+fn read_run(reader: &mut BufReader<File>) -> Result<Option<KeyValue>> {
+    match bincode::deserialize_from(reader) {
+        Ok(kv) => Ok(Some(kv)),
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            e => Err(anyhow::anyhow!("Failed to read sorted run: {}", e)),
+        },
+    }
+}
+
+/// Sorted pairs returned by `ExternalSorter::finish`
+pub enum Sorted {
+    Memory(std::vec::IntoIter<KeyValue>),
+    Merge(Merge),
+}
+
+/// Merge of the spilled runs, reading one pair ahead from each into a heap whose root
+/// is the next pair
+pub struct Merge {
+    order: SortOrder,
+    readers: Vec<BufReader<File>>,
+    heads: BinaryHeap<Reverse<Head>>,
+    // Keeps the run files around until the merge is done
+    _sorter: ExternalSorter,
+}
+
+impl Iterator for Sorted {
+    type Item = Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let merge = match self {
+            Sorted::Memory(pairs) => return pairs.next().map(Ok),
+            Sorted::Merge(merge) => merge,
+        };
+        let Reverse(head) = merge.heads.pop()?;
+        match read_run(&mut merge.readers[head.run]) {
+            Ok(Some(kv)) => merge.heads.push(Reverse(Head {
+                ranked: Ranked {
+                    order: merge.order,
+                    kv,
+                },
+                run: head.run,
+            })),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(head.ranked.kv))
+    }
+}
+
+/// Next pair of a spilled run, for the heap of `Merge`
+struct Head {
+    ranked: Ranked,
+    run: usize,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ranked
+            .cmp(&other.ranked)
+            .then_with(|| self.run.cmp(&other.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Keeps the first `n` pairs in sort order, for `--top`, in a heap whose root is the
+/// last of them.
+pub struct TopN {
+    order: SortOrder,
+    n: usize,
+    heap: BinaryHeap<Ranked>,
+}
+
+impl TopN {
+    pub fn new(order: SortOrder, n: usize) -> Self {
+        TopN {
+            order,
+            n,
+            // `--top` may be far larger than the number of keys, so the heap grows as needed
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, kv: KeyValue) {
+        let ranked = Ranked {
+            order: self.order,
+            kv,
+        };
+        if self.heap.len() < self.n {
+            self.heap.push(ranked);
+        } else if let Some(mut last) = self.heap.peek_mut()
+            && ranked < *last
+        {
+            *last = ranked;
+        }
+    }
+
+    /// The kept pairs in sort order
+    pub fn finish(self) -> Vec<KeyValue> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| ranked.kv)
+            .collect()
+    }
+}
+
+/// Pair ordered by the sort order, for the heaps of `TopN` and `Merge`
+struct Ranked {
+    order: SortOrder,
+    kv: KeyValue,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order.compare(&self.kv, &other.kv)
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
@@ -263,7 +263,7 @@ EOF
 
   # Test with custom script file
   run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
-  [ "$status" -eq 0 ]
+  [ "$status" -eq 1 ]
   [[ "$output" =~ "Error loading JS code: Error: unexpected token in expression: 'var'" ]]
   rm -rf "$TMPDIR"
 }
@@ -381,3 +381,1102 @@ EOF
 
   rm -rf "$TMPDIR"
 }
+
+@test "ndjson input" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.ndjson"
+  SCRIPTFILE="$TMPDIR/script.js"
+  OUTFILE="$TMPDIR/out.txt"
+
+  cat > "$TESTFILE" << 'EOF'
+{"status": 200, "bytes": 10}
+{"status": 404, "bytes": 5}
+not json
+{"status": 200, "bytes": 7}
+EOF
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (record) => [[String(record.status), record.bytes]];
+const reduce = async (key, values) => values.reduce((sum, v) => sum + v, 0);
+EOF
+
+  # Lines that don't parse are skipped, but count as lost records
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --input-format=ndjson
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost (first error: Error parsing JSON on line 3" ]]
+  [[ "$output" =~ "failed records: $TESTFILE:3" ]]
+  [[ "$output" =~ "200: 17" ]]
+  [[ "$output" =~ "404: 5" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --input-format=ndjson --on-error dead-letter --dead-letter "$OUTFILE"
+  [ "$status" -eq 2 ]
+  [[ "$(cat "$OUTFILE")" =~ '"input":"not json"' ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "csv input" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.csv"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  printf 'name,city,note\nalice,Lisbon,"likes ""quotes"", commas"\nbob,Porto,"spans\ntwo lines"\ncarol,Lisbon,\n' > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (record) => [[record.city, record.note.length]];
+const reduce = async (key, values) => values.join(",");
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --input-format=csv
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 2 ]]
+  [[ "$output" =~ "Lisbon: " ]]
+  [[ "$output" =~ "Porto: 15" ]]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (fields) => [[fields[0], fields.length]];
+const reduce = async (key, values) => values[0];
+EOF
+
+  printf 'a\t1\t2\nb\t3\n' > "$TESTFILE"
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --input-format=tsv --no-header
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 3" ]]
+  [[ "$output" =~ "b: 2" ]]
+
+  # Records with invalid UTF-8 can be skipped, but not the header
+  printf 'na\xffme,n\na,1\nb,2\n' > "$TESTFILE"
+  run "$BIN" -f "$TESTFILE" --input-format=csv --map '[[line.name, 1]]' --invalid-utf8 skip
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Invalid UTF-8 in the header on line 1" ]]
+
+  run "$BIN" -f "$TESTFILE" --input-format=csv --no-header --map '[[line[0], 1]]' --invalid-utf8 skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 1" ]]
+  [[ "$output" =~ "failed records: $TESTFILE:1" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "multiple input files, globs and directories" {
+  TMPDIR=$(mktemp -d)
+  SCRIPTFILE="$TMPDIR/script.js"
+  mkdir -p "$TMPDIR/logs/nested"
+  echo -e "a\nb" > "$TMPDIR/logs/one.log"
+  echo "c" > "$TMPDIR/logs/two.log"
+  echo "d" > "$TMPDIR/logs/nested/three.log"
+  echo "e" > "$TMPDIR/extra.txt"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line, { file, line: lineNo }) => [[file.split("/").pop(), `${line}@${lineNo}`]];
+const reduce = async (key, values) => values.sort().join(",");
+EOF
+
+  run "$BIN" -f "$TMPDIR/logs/*.log" "$TMPDIR/extra.txt" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ "one.log: a@1,b@2" ]]
+  [[ "$output" =~ "two.log: c@1" ]]
+  [[ "$output" =~ "extra.txt: e@1" ]]
+
+  run "$BIN" -f "$TMPDIR/logs" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ "three.log: d@1" ]]
+
+  run "$BIN" -f "$TMPDIR/missing/*.log" -s "$SCRIPTFILE"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "No files match" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "compressed input" {
+  TMPDIR=$(mktemp -d)
+  echo "hello world hello" > "$TMPDIR/test.txt"
+  gzip -c "$TMPDIR/test.txt" > "$TMPDIR/test.txt.gz"
+  bzip2 -c "$TMPDIR/test.txt" > "$TMPDIR/test.bin"
+
+  run "$BIN" -f "$TMPDIR/test.txt.gz"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "hello: 2" ]]
+  [[ "$output" =~ "world: 1" ]]
+
+  # detected from magic bytes, without a matching extension
+  run "$BIN" -f "$TMPDIR/test.bin"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "hello: 2" ]]
+
+  run bash -c "cat '$TMPDIR/test.txt.gz' '$TMPDIR/test.txt.gz' | '$BIN'"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "hello: 4" ]]
+  [[ "$output" =~ "world: 2" ]]
+
+  # magic bytes arriving over several reads
+  run bash -c "(head -c 1 '$TMPDIR/test.txt.gz'; sleep 0.2; tail -c +2 '$TMPDIR/test.txt.gz') | '$BIN'"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "hello: 2" ]]
+
+  # text that happens to start like a bzip2 header
+  echo "BZhello world" > "$TMPDIR/bzh.txt"
+  run "$BIN" -f "$TMPDIR/bzh.txt"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "bzhello: 1" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "parallel byte-range splits" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  for i in $(seq 1 2000); do echo "line $i word$((i % 7))"; done > "$TESTFILE"
+
+  "$BIN" -f "$TESTFILE" | sort > "$TMPDIR/sequential.txt"
+  "$BIN" -f "$TESTFILE" --split-size 1000 | sort > "$TMPDIR/split.txt"
+  run diff "$TMPDIR/sequential.txt" "$TMPDIR/split.txt"
+  [ "$status" -eq 0 ]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line, { offset }) => [["lines", 1], ["offset", offset]];
+const reduce = async (key, values) => key === "lines" ? values.length : Math.min(...values);
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --split-size 1000
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "lines: 2000" ]]
+  [[ "$output" =~ "offset: 0" ]]
+
+  # Without --split-size, large files are read in order with line numbers
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line, meta) => [[Object.keys(meta).join(","), meta.line]];
+const reduce = async (key, values) => Math.max(...values);
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [ "$output" = "file,line: 2000" ]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "custom record separators and multi-line records" {
+  TMPDIR=$(mktemp -d)
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (record, { line }) => [[JSON.stringify(record), line]];
+const reduce = async (key, values) => values[0];
+EOF
+
+  run bash -c "printf 'a\0b\nc\0d' | '$BIN' -s '$SCRIPTFILE' --record-separator '\0'"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ '"b\nc": 1' ]]
+  [[ "$output" =~ '"d": 2' ]]
+
+  run bash -c "printf '\n\nfirst\nparagraph\n\n\nsecond\n' | '$BIN' -s '$SCRIPTFILE' --record-separator ''"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 2 ]]
+  [[ "$output" =~ '"first\nparagraph": 3' ]]
+  [[ "$output" =~ '"second": 7' ]]
+
+  run bash -c "printf 'INFO start\nERROR boom\n  at foo\n  at bar\nINFO done\n' | '$BIN' -s '$SCRIPTFILE' --record-start '^[A-Z]+ '"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ '"ERROR boom\n  at foo\n  at bar": 2' ]]
+  [[ "$output" =~ '"INFO done": 5' ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "invalid utf-8 input policies" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  printf 'good line\nbad \xff line\nfine\n' > "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE"
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost (first error: Invalid UTF-8 on line 2" ]]
+  [[ "$output" =~ "line: 1" ]]
+
+  run "$BIN" -f "$TESTFILE" --invalid-utf8 lossy
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "line: 2" ]]
+  [[ "$output" =~ "bad: 1" ]]
+
+  run "$BIN" -f "$TESTFILE" --invalid-utf8 fail
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Invalid UTF-8 on line 2" ]]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (bytes) => [[String(bytes instanceof Uint8Array), bytes.length]];
+const reduce = async (key, values) => values.join(",");
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --invalid-utf8 bytes
+  [ "$status" -eq 0 ]
+  [[ "$output" == "true: 9,10,4" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "emit callback" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  printf '3000\n2\nskip\n' > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => {
+  if (line === "skip") return [["returned", 1]];
+  for (let i = 0; i < Number(line); i++) {
+    await emit(String(i % 3), 1);
+  }
+};
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "0: 1001" ]]
+  [[ "$output" =~ "1: 1001" ]]
+  [[ "$output" =~ "2: 1000" ]]
+  [[ "$output" =~ "returned: 1" ]]
+
+  # Pairs emitted without await pile up, until emit throws
+  sed -i 's/await emit/emit/' "$SCRIPTFILE"
+  printf '500\n500\n500\n' > "$TMPDIR/small.txt"
+  run "$BIN" -f "$TMPDIR/small.txt" -s "$SCRIPTFILE" -j 1 -c 1
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "0: 501" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "emitted pairs are waiting to be sent, await emit()" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "generator map output" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  printf 'a b c\na b d\n' > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+async function* map(line) {
+  const words = line.split(" ");
+  for (let i = 0; i + 1 < words.length; i++) {
+    yield [words[i] + " " + words[i + 1], 1];
+  }
+}
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ "a b: 2" ]]
+  [[ "$output" =~ "b d: 1" ]]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => new Set(line.split(" ").map((word) => [word, 1]));
+const combine = async (pairs) => pairs.filter(([word]) => word !== "c");
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ "a: 2" ]]
+
+  # A string isn't taken apart into pairs of characters
+  run "$BIN" -f "$TESTFILE" --map 'line' --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "map phase: 2 failures, 2 records lost" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "setup and teardown hooks" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  printf 'a\nb\na\nc\n' > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+let names;
+const setup = async () => {
+  names = new Map([["a", "alpha"], ["b", "beta"]]);
+};
+const teardown = async () => {
+  names = undefined;
+};
+const map = async (line) => [[names.get(line) ?? "other", 1]];
+const reduce = async (key, values) => values.length;
+const test = async () => {
+  if (names.get("a") !== "alpha") throw new Error("setup did not run");
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 3 ]]
+  [[ "$output" =~ "alpha: 2" ]]
+  [[ "$output" =~ "other: 1" ]]
+
+  run "$BIN" -s "$SCRIPTFILE" --test
+  [ "$status" -eq 0 ]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const setup = async () => { throw new Error("missing dictionary"); };
+const map = async (line) => [[line, 1]];
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [[ "$output" =~ "Error in setup: Error: missing dictionary" ]]
+  [[ ! "$output" =~ "a: 2" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "job parameters" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  PARAMSFILE="$TMPDIR/params.json"
+  printf '1\n5\n10\n' > "$TESTFILE"
+  echo '{"threshold": 1, "label": "all", "range": {"from": 0}}' > "$PARAMSFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) =>
+  Number(line) >= Number(params.threshold) ? [[params.label, Number(line)]] : [];
+const reduce = async (key, values) => values.sort((a, b) => a - b).join(",");
+const test = async () => {
+  if (!Object.isFrozen(params.range)) throw new Error("params are not frozen");
+  if (params.label !== "big") throw new Error(`unexpected label ${params.label}`);
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --params-file "$PARAMSFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" == "all: 1,5,10" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --params-file "$PARAMSFILE" --param threshold=5 --param label=big
+  [ "$status" -eq 0 ]
+  [[ "$output" == "big: 5,10" ]]
+
+  run "$BIN" -s "$SCRIPTFILE" --params-file "$PARAMSFILE" --param label=big --test
+  [ "$status" -eq 0 ]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --param threshold
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "--param must be KEY=VALUE" ]]
+
+  # Scripts can declare a `params` of their own
+  cat > "$SCRIPTFILE" << 'EOF'
+const params = { label: "own" };
+const map = async (line) => [[params.label, Number(line)]];
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --params-file "$PARAMSFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" == "own: 3" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "inline scripts" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.csv"
+  printf 'a,1\nb,2\na,3\n' > "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE" --map 'line.split(",").slice(0, 1).map((k) => [k, 1])'
+  [ "$status" -eq 0 ]
+  [[ $(echo "$output" | wc -l) -eq 2 ]]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+
+  run "$BIN" -f "$TESTFILE" --map '[line.split(",")]' --reduce 'values.map(Number).reduce((a, b) => a + b)'
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 4" ]]
+  [[ "$output" =~ "b: 2" ]]
+
+  run "$BIN" -f "$TESTFILE" -e 'const map = async (line) => [[line[0], 1]]; const reduce = async (key, values) => values.length;'
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 2" ]]
+
+  run "$BIN" -f "$TESTFILE" -e 'const reduce = async (key, values) => values.sort().join("|");' --map '[line.split(",")]'
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 1|3" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "es module scripts with imports" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.csv"
+  SCRIPTFILE="$TMPDIR/job.js"
+  mkdir -p "$TMPDIR/lib"
+  printf 'a,1\nb,2\na,3\n' > "$TESTFILE"
+
+  cat > "$TMPDIR/lib/parse.js" << 'EOF'
+export const fields = (line) => line.split(",");
+EOF
+  cat > "$SCRIPTFILE" << 'EOF'
+import { fields } from "./lib/parse.js";
+const offset = await Promise.resolve(10);
+export const map = async (line) => [[fields(line)[0], Number(fields(line)[1]) + offset]];
+export const reduce = async (key, values) => values.reduce((a, b) => a + b, 0);
+export const test = async () => {
+  if (fields("x,y").length !== 2) throw new Error("import failed");
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 24" ]]
+  [[ "$output" =~ "b: 12" ]]
+
+  run "$BIN" -s "$SCRIPTFILE" --test
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "OK" ]]
+
+  # Lines that only look like imports and exports keep a script classic
+  cat > "$SCRIPTFILE" << 'EOF'
+const usage = `
+import data from the source
+export the results`;
+/*
+export nothing
+*/
+const map = async (line) => [[line.split(",")[0], 1]];
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 2" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "typescript scripts" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.csv"
+  SCRIPTFILE="$TMPDIR/job.ts"
+  printf 'a,1\nb,2\na,3\n' > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+interface Row {
+  key: string;
+  amount: number;
+}
+
+const parse = (line: string): Row => {
+  const [key, amount] = line.split(",");
+  return { key, amount: Number(amount) };
+};
+
+const map = async (line: string): Promise<[string, number][]> => {
+  const row = parse(line) as Row;
+  return [[row.key, row.amount]];
+};
+const reduce = async (key: string, values: number[]): Promise<number> =>
+  values.reduce((a, b) => a + b, 0);
+const test = async (): Promise<void> => {
+  throw new Error("failing test");
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 4" ]]
+  [[ "$output" =~ "b: 2" ]]
+
+  run "$BIN" -s "$SCRIPTFILE" --test
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "job.ts:18" ]]
+
+  printf 'enum Color { Red }\n' > "$SCRIPTFILE"
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Failed to strip types" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "compiled bytecode scripts" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.csv"
+  SCRIPTFILE="$TMPDIR/job.js"
+  mkdir -p "$TMPDIR/lib"
+  printf 'a,1\nb,2\na,3\n' > "$TESTFILE"
+
+  cat > "$TMPDIR/lib/parse.js" << 'EOF'
+export const fields = (line) => line.split(",");
+EOF
+  cat > "$SCRIPTFILE" << 'EOF'
+import { fields } from "./lib/parse.js";
+export const map = async (line) => [[fields(line)[0], Number(fields(line)[1])]];
+export const reduce = async (key, values) => values.reduce((a, b) => a + b, 0);
+EOF
+
+  # Imported files would be looked up at this machine's paths, so they need bundling
+  run "$BIN" compile "$SCRIPTFILE"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "./lib/parse.js" ]]
+  [ ! -f "$TMPDIR/job.pbc" ]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+import * as buffer from "buffer";
+const fields = (line) => line.split(",");
+export const map = async (line) => [[fields(line)[0], Number(fields(line)[1])]];
+export const reduce = async (key, values) => values.reduce((a, b) => a + b, 0);
+EOF
+
+  # A subcommand isn't taken for one of the input files
+  run "$BIN" -f "$TESTFILE" "$TESTFILE" compile "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [ -f "$TMPDIR/job.pbc" ]
+
+  # Only the script argument is compiled, so other ways of giving a script are refused
+  for flags in "--map [[line,1]]" "-e 0" "-s $SCRIPTFILE"; do
+    run "$BIN" $flags compile "$SCRIPTFILE" -o "$TMPDIR/other.pbc"
+    [ "$status" -eq 1 ]
+    [[ "$output" =~ "can't be combined with compile" ]]
+    [ ! -f "$TMPDIR/other.pbc" ]
+  done
+
+  rm "$SCRIPTFILE"
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/job.pbc"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 4" ]]
+  [[ "$output" =~ "b: 2" ]]
+
+  # Classic scripts run in strict mode
+  cat > "$SCRIPTFILE" << 'EOF'
+counted = 0;
+const map = async (line) => [[line.split(",")[0], 1]];
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "counted" ]]
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => [[line.split(",")[0], 1]];
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --bytecode-cache "$TMPDIR/cache"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 2" ]]
+  [ $(ls "$TMPDIR/cache" | wc -l) -eq 1 ]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --bytecode-cache "$TMPDIR/cache"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 2" ]]
+  [ $(ls "$TMPDIR/cache" | wc -l) -eq 1 ]
+
+  # A damaged cache entry is recompiled instead of failing the job
+  echo "garbage" > "$TMPDIR/cache/$(ls "$TMPDIR/cache")"
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --bytecode-cache "$TMPDIR/cache"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: 2" ]]
+  [ $(ls "$TMPDIR/cache" | wc -l) -eq 1 ]
+
+  echo "garbage" > "$TMPDIR/bad.pbc"
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/bad.pbc"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Failed to read bytecode file" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "failure policy" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  echo -e "a\nb\na" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => [[line, 1]];
+const reduce = async (key, values) => {
+  if (key === "b") throw new Error("bad key");
+  return values.length;
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -c 1
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Error in reduce phase: JavaScript error: Error: bad key" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -c 1 --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "reduce phase: 1 failures, 1 keys lost" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -c 1 --on-error dead-letter --dead-letter "$TMPDIR/failed.ndjson"
+  [ "$status" -eq 2 ]
+  [[ "$(cat "$TMPDIR/failed.ndjson")" =~ '"key":"b","phase":"reduce"' ]]
+
+  run "$BIN" -f "$TESTFILE" --map '[[line, 1]]'
+  [ "$status" -eq 0 ]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "dead-letter records" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  DEADLETTER="$TMPDIR/failed.ndjson"
+
+  echo -e "a\nbad\nb\na" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => {
+  if (line === "bad") throw new Error("malformed line");
+  return [[line, 1]];
+};
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --on-error dead-letter --dead-letter "$DEADLETTER"
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost (first error: Error: malformed line)" ]]
+  [ "$(wc -l < "$DEADLETTER")" -eq 1 ]
+  [[ "$(cat "$DEADLETTER")" =~ '"error":"Error: malformed line","input":"bad","meta":{"file":' ]]
+  [[ "$(cat "$DEADLETTER")" =~ '"line":2},"phase":"map","stack":"    at map (' ]]
+
+  # Entries and the summary are kept when the job fails later on
+  (echo bad; seq 1 100; printf 'x\xff\n') > "$TESTFILE"
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 -c 1 --invalid-utf8 fail --on-error dead-letter --dead-letter "$DEADLETTER"
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Invalid UTF-8 on line 102" ]]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost" ]]
+  [ "$(wc -l < "$DEADLETTER")" -eq 1 ]
+  [[ "$(cat "$DEADLETTER")" =~ '"input":"bad"' ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "retries and failed record report" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  echo -e "a\nbad\nb\na" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const attempts = new Map();
+const map = async (line) => {
+  const attempt = (attempts.get(line) ?? 0) + 1;
+  attempts.set(line, attempt);
+  if (line === "bad" || attempt === 1) throw new Error(`attempt ${attempt} for ${line}`);
+  return [[line, 1]];
+};
+const reduce = async (key, values) => {
+  if (key === "b") throw new Error("bad key");
+  return values.length;
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --on-error skip --retries 2
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost (first error: Error: attempt 3 for bad)" ]]
+  [[ "$output" =~ "failed records: $TESTFILE:2" ]]
+  [[ "$output" =~ "failed keys: b" ]]
+
+  # Pairs emitted by a failed attempt are dropped with it
+  cat > "$SCRIPTFILE" << 'EOF'
+const attempts = new Map();
+const map = async (line, meta, emit) => {
+  const attempt = (attempts.get(line) ?? 0) + 1;
+  attempts.set(line, attempt);
+  await emit(line, attempt);
+  if (line === "bad" || attempt === 1) throw new Error(`attempt ${attempt} for ${line}`);
+  await emit("ok", attempt);
+};
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --on-error skip --retries 2
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+  [[ "$output" =~ "ok: 3" ]]
+  [[ ! "$output" =~ "bad:" ]]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost (first error: Error: attempt 3 for bad)" ]]
+
+  # The global emit can't tell records apart, so it refuses to run then
+  sed -i 's/await emit(line/await globalThis.emit(line/' "$SCRIPTFILE"
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "emit() is passed to map as its third argument" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "record and job timeouts" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  echo -e "a\nspin\nb\nhang\na" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => {
+  if (line === "spin") while (true) {}
+  if (line === "hang") await new Promise(() => {});
+  return [[line, 1]];
+};
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --record-timeout 0.2 --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+  [[ "$output" =~ "map phase: 2 failures, 2 records lost (first error: Error: timed out after 0.2s)" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --job-timeout 0.5
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Job timed out after 0.5s" ]]
+
+  # Records lost before the job times out are still reported
+  printf 'a\nx\xff\nhang\n' > "$TMPDIR/invalid.txt"
+  run "$BIN" -f "$TMPDIR/invalid.txt" -s "$SCRIPTFILE" --job-timeout 0.5 --invalid-utf8 skip
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost" ]]
+  [[ "$output" =~ "Job timed out after 0.5s" ]]
+
+  # Generators are consumed within the record's deadline, as is combine
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async function* (line) {
+  yield [line, 1];
+  if (line === "spin") while (true) {}
+  if (line === "hang") await new Promise(() => {});
+};
+const reduce = async (key, values) => values.length;
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --record-timeout 0.2 --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+  [[ "$output" =~ "map phase: 2 failures, 2 records lost (first error: Error: timed out after 0.2s)" ]]
+
+  cat >> "$SCRIPTFILE" << 'EOF'
+const combine = async (pairs) => {
+  if (pairs[0][0] === "b") while (true) {}
+  return pairs;
+};
+EOF
+  echo -e "a
+b
+a" > "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --record-timeout 0.2 --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "map phase: 1 failures, 1 records lost" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "vm memory and stack limits" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  echo -e "a\nhog\nb\nrecurse\na" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => {
+  if (line === "hog") {
+    const chunks = [];
+    while (true) chunks.push(new Array(10000).fill(line));
+  }
+  if (line === "recurse") {
+    const deeper = (depth) => deeper(depth + 1) + 1;
+    deeper(0);
+  }
+  return [[line, 1]];
+};
+const reduce = async (key, values) => values.length;
+const test = async () => {
+  const chunks = [];
+  while (true) chunks.push(new Array(10000).fill(0));
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --vm-memory-limit 64M --vm-stack-size 512K --on-error skip
+  [ "$status" -eq 2 ]
+  [[ "$output" =~ "a: 2" ]]
+  [[ "$output" =~ "b: 1" ]]
+  [[ "$output" =~ "map phase: 2 failures, 2 records lost" ]]
+
+  run "$BIN" -s "$SCRIPTFILE" --vm-memory-limit 64M --test
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "out of memory" ]]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "sandbox mode" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  PURESCRIPT="$TMPDIR/pure.js"
+
+  echo "a" > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+import fs from 'fs';
+const map = async (line) => [[line, `${typeof fetch} ${typeof fs}`]];
+const reduce = async (key, values) => values[0];
+export { map, reduce };
+EOF
+
+  cat > "$PURESCRIPT" << 'EOF'
+import path from 'path';
+const map = async (line) => [[line, `${typeof fetch} ${typeof path}`]];
+const reduce = async (key, values) => values[0];
+export { map, reduce };
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: function object" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --sandbox
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "Error loading JS code" ]]
+  [[ "$output" =~ "'fs'" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$PURESCRIPT" --sandbox
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: undefined object" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --sandbox --sandbox-allow fs,fetch
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: function object" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --sandbox-allow fs
+  [ "$status" -ne 0 ]
+
+  # Files can't be imported or required from the sandbox
+  echo 'export const secret = "leaked";' > "$TMPDIR/secret.mjs"
+  cat > "$TMPDIR/import.mjs" << 'EOF'
+import { secret } from "./secret.mjs";
+export const map = async (line) => [[line, secret]];
+export const reduce = async (key, values) => values[0];
+EOF
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/import.mjs"
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: leaked" ]]
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/import.mjs" --sandbox
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "./secret.mjs" ]]
+
+  cat > "$TMPDIR/dynamic.js" << 'EOF'
+const map = async (line) => [[line, `${typeof require} ${await import("/etc/hostname").then(() => "imported", () => "blocked")}`]];
+const reduce = async (key, values) => values[0];
+EOF
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/dynamic.js" --sandbox
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "a: undefined blocked" ]]
+
+  # Bytecode isn't validated, so it's never loaded into a sandbox
+  run "$BIN" compile "$PURESCRIPT" -o "$TMPDIR/pure.pbc"
+  [ "$status" -eq 0 ]
+  run "$BIN" -f "$TESTFILE" -s "$TMPDIR/pure.pbc" --sandbox
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "can't be run with --sandbox" ]]
+  run "$BIN" -f "$TESTFILE" -s "$PURESCRIPT" --sandbox --bytecode-cache "$TMPDIR/cache"
+  [ "$status" -ne 0 ]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "deterministic mode" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+
+  seq 1 200 > "$TESTFILE"
+
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => [[line, [
+  Math.random(),
+  Date.now(),
+  new Date().toISOString(),
+  performance.now(),
+  crypto.randomUUID(),
+  crypto.getRandomValues(new Uint32Array(2)).join(","),
+].join(" ")]];
+const reduce = async (key, values) => values[0];
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --deterministic --seed 42 --now 86400000
+  [ "$status" -eq 0 ]
+  FIRST="$output"
+  [[ "$output" =~ "86400000 1970-01-02T00:00:00.000Z 0 " ]]
+  [[ "$output" =~ [0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12} ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --deterministic --seed 42 --now 86400000
+  [ "$status" -eq 0 ]
+  [ "$output" = "$FIRST" ]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" -j 1 --deterministic --seed 43 --now 86400000
+  [ "$status" -eq 0 ]
+  [ "$output" != "$FIRST" ]
+
+  # Output comes in key order whichever reduce batch finishes first
+  run "$BIN" -f "$TESTFILE" -j 4 -c 3 --deterministic
+  [ "$status" -eq 0 ]
+  [ "$output" = "$(printf '%s: 1\n' $(seq 1 200) | LC_ALL=C sort -t: -k1,1)" ]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --seed 42
+  [ "$status" -ne 0 ]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "spilled groups" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+  SCRIPTFILE="$TMPDIR/script.js"
+  cd "$TMPDIR"
+
+  seq 1 5000 | awk '{ print "w" ($1 % 300) }' > "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE" --sort
+  [ "$status" -eq 0 ]
+  EXPECTED="$output"
+
+  # Every batch of map output is spilled, and read back with the same result
+  RUST_LOG=info run "$BIN" -f "$TESTFILE" --sort --group-buffer-size 1
+  [ "$status" -eq 0 ]
+  [[ "$output" =~ "Flushing" ]]
+
+  run "$BIN" -f "$TESTFILE" --sort --group-buffer-size 1
+  [ "$status" -eq 0 ]
+  [ "$output" = "$EXPECTED" ]
+
+  # Failures while reducing spilled groups end the job
+  cat > "$SCRIPTFILE" << 'EOF'
+const map = async (line) => [[line, 1]];
+const reduce = async (key, values) => {
+  if (key === "w7") throw new Error("bad key");
+  return values.length;
+};
+EOF
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --group-buffer-size 1
+  [ "$status" -eq 1 ]
+  [[ "$output" =~ "bad key" ]]
+
+  run "$BIN" -f "$TESTFILE" -s "$SCRIPTFILE" --group-buffer-size 1 --on-error skip
+  [ "$status" -eq 2 ]
+  [ "$(echo "$output" | grep -c "^w[0-9]*: ")" -eq 299 ]
+  [[ "$output" =~ "failed keys: w7" ]]
+
+  run "$BIN" -f "$TESTFILE" --group-buffer-size 1X
+  [ "$status" -ne 0 ]
+
+  [ ! -e "$TMPDIR/pulsar_groups" ]
+  cd - > /dev/null
+  rm -rf "$TMPDIR"
+}
+
+@test "native sort" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+
+  seq 1 2000 | shuf > "$TESTFILE"
+  echo -e "b\nb\nc\na\na\na" >> "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE" --sort-by key --numeric
+  [ "$status" -eq 0 ]
+  [ "$output" = "$( (seq 1 2000 | sed 's/$/: 1/'; echo -e "a: 3\nb: 2\nc: 1") )" ]
+
+  # Spilled to many small runs and merged, with the same result
+  run "$BIN" -f "$TESTFILE" --sort-by key --numeric --sort-buffer-size 2K
+  [ "$status" -eq 0 ]
+  [ "$output" = "$( (seq 1 2000 | sed 's/$/: 1/'; echo -e "a: 3\nb: 2\nc: 1") )" ]
+
+  run "$BIN" -f "$TESTFILE" --sort-by key --desc --sort-buffer-size 2K
+  [ "$status" -eq 0 ]
+  [ "$(echo "$output" | head -n 3)" = "$(echo -e "c: 1\nb: 2\na: 3")" ]
+  [ "$(echo "$output" | tail -n 1)" = "1: 1" ]
+
+  # Non-numbers sort last in either direction
+  run "$BIN" -f "$TESTFILE" --sort-by key --numeric --desc --sort-buffer-size 2K
+  [ "$status" -eq 0 ]
+  [ "$(echo "$output" | head -n 2)" = "$(echo -e "2000: 1\n1999: 1")" ]
+  [ "$(echo "$output" | tail -n 4)" = "$(echo -e "1: 1\na: 3\nb: 2\nc: 1")" ]
+
+  run "$BIN" -f "$TESTFILE" --top 2 --sort-by key --numeric --desc
+  [ "$status" -eq 0 ]
+  [ "$output" = "$(echo -e "2000: 1\n1999: 1")" ]
+
+  # Ties on the value are ordered by key
+  run "$BIN" -f "$TESTFILE" --sort-by value --numeric --desc
+  [ "$status" -eq 0 ]
+  [ "$(echo "$output" | head -n 4)" = "$(echo -e "a: 3\nb: 2\n1: 1\n10: 1")" ]
+
+  run "$BIN" -f "$TESTFILE" --sort --sort-by key
+  [ "$status" -ne 0 ]
+
+  run "$BIN" -f "$TESTFILE" --desc
+  [ "$status" -ne 0 ]
+
+  rm -rf "$TMPDIR"
+}
+
+@test "top n" {
+  TMPDIR=$(mktemp -d)
+  TESTFILE="$TMPDIR/test.txt"
+
+  for i in $(seq 1 300); do
+    for j in $(seq 1 $((i % 17))); do echo "w$i"; done
+  done | shuf > "$TESTFILE"
+
+  run "$BIN" -f "$TESTFILE" --top 5 --by value --desc --numeric
+  [ "$status" -eq 0 ]
+  [ "$output" = "$(echo -e "w101: 16\nw118: 16\nw135: 16\nw152: 16\nw16: 16")" ]
+
+  run "$BIN" -f "$TESTFILE" --top 3 --sort-by key
+  [ "$status" -eq 0 ]
+  [ "$output" = "$(echo -e "w1: 1\nw10: 10\nw100: 15")" ]
+
+  # Same as the head of a full sort
+  run "$BIN" -f "$TESTFILE" --top 40 --by value --numeric
+  [ "$status" -eq 0 ]
+  TOP="$output"
+  run "$BIN" -f "$TESTFILE" --sort-by value --numeric
+  [ "$TOP" = "$(echo "$output" | head -n 40)" ]
+
+  # A limit larger than the number of keys keeps them all
+  run "$BIN" -f "$TESTFILE" --top 18446744073709551615 --by value --numeric
+  [ "$status" -eq 0 ]
+  [ "$(echo "$output" | wc -l)" -eq 283 ]
+
+  run "$BIN" -f "$TESTFILE" --top 5
+  [ "$status" -ne 0 ]
+
+  rm -rf "$TMPDIR"
+}